@@ -0,0 +1,17 @@
+-- Create the 'messages' table holding every raw Discord message we listen to. Messages are
+-- batched into summaries by token budget; 'summary_id' stays NULL until they are summarized.
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY NOT NULL, -- Discord message id
+    channel_id INTEGER NOT NULL,
+    guild_id INTEGER,
+    author_id INTEGER NOT NULL,
+    display_name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp DATETIME NOT NULL,
+    reply_to INTEGER,
+    edited_at DATETIME,
+    summary_id INTEGER,
+    FOREIGN KEY (summary_id) REFERENCES summaries(id)
+);
+
+CREATE INDEX messages_summary_id_timestamp ON messages (summary_id, timestamp);
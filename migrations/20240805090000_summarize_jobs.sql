@@ -0,0 +1,20 @@
+-- Create the 'summarize_jobs' table, a durable queue of message batches waiting to be
+-- summarized. Jobs left 'in_progress' by a crash are picked up again on startup.
+CREATE TABLE summarize_jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    status TEXT NOT NULL DEFAULT 'pending', -- pending, in_progress, done or failed
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    summary_id INTEGER,
+    next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (summary_id) REFERENCES summaries(id)
+);
+
+CREATE INDEX summarize_jobs_status_next_attempt_at ON summarize_jobs (status, next_attempt_at);
+
+-- Each message is queued in at most one job.
+ALTER TABLE messages ADD COLUMN job_id INTEGER REFERENCES summarize_jobs(id);
+
+CREATE INDEX messages_job_id ON messages (job_id);
@@ -0,0 +1,16 @@
+-- Key summaries and digests by the Discord channel (and guild) they cover. Rows created before
+-- this migration mixed every channel together and keep a NULL channel.
+ALTER TABLE summaries ADD COLUMN channel_id INTEGER;
+ALTER TABLE summaries ADD COLUMN guild_id INTEGER;
+
+ALTER TABLE daily_digests ADD COLUMN channel_id INTEGER;
+ALTER TABLE daily_digests ADD COLUMN guild_id INTEGER;
+-- Roll-up digests combine the per-channel digests of a guild and have no channel of their own.
+ALTER TABLE daily_digests ADD COLUMN rollup BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Jobs batch messages from a single channel.
+ALTER TABLE summarize_jobs ADD COLUMN channel_id INTEGER;
+ALTER TABLE summarize_jobs ADD COLUMN guild_id INTEGER;
+
+CREATE INDEX summaries_channel_id_daily_digest_id ON summaries (channel_id, daily_digest_id);
+CREATE INDEX daily_digests_channel_id_timestamp ON daily_digests (channel_id, timestamp);
@@ -0,0 +1,2 @@
+-- The first Discord message a digest was posted as, if it was posted.
+ALTER TABLE daily_digests ADD COLUMN discord_message_id INTEGER;
@@ -0,0 +1,11 @@
+-- Bearer tokens for the HTTP API. Only a SHA-256 hash of each token is kept; the token itself
+-- is shown once, when it is created.
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL, -- comma-separated: read, trigger, admin
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME,
+    revoked_at DATETIME
+);
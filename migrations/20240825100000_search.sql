@@ -0,0 +1,70 @@
+-- Full-text indexes over messages, summaries and digests. Each indexes its table's text without
+-- storing a copy of it (external content), and is kept up to date by triggers.
+CREATE VIRTUAL TABLE messages_fts USING fts5(
+    content,
+    display_name,
+    content = 'messages',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts (rowid, content, display_name)
+    VALUES (new.id, new.content, new.display_name);
+END;
+
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content, display_name)
+    VALUES ('delete', old.id, old.content, old.display_name);
+END;
+
+CREATE TRIGGER messages_fts_update AFTER UPDATE OF content, display_name ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content, display_name)
+    VALUES ('delete', old.id, old.content, old.display_name);
+    INSERT INTO messages_fts (rowid, content, display_name)
+    VALUES (new.id, new.content, new.display_name);
+END;
+
+CREATE VIRTUAL TABLE summaries_fts USING fts5(
+    text,
+    content = 'summaries',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER summaries_fts_insert AFTER INSERT ON summaries BEGIN
+    INSERT INTO summaries_fts (rowid, text) VALUES (new.id, new.text);
+END;
+
+CREATE TRIGGER summaries_fts_delete AFTER DELETE ON summaries BEGIN
+    INSERT INTO summaries_fts (summaries_fts, rowid, text) VALUES ('delete', old.id, old.text);
+END;
+
+CREATE TRIGGER summaries_fts_update AFTER UPDATE OF text ON summaries BEGIN
+    INSERT INTO summaries_fts (summaries_fts, rowid, text) VALUES ('delete', old.id, old.text);
+    INSERT INTO summaries_fts (rowid, text) VALUES (new.id, new.text);
+END;
+
+CREATE VIRTUAL TABLE daily_digests_fts USING fts5(
+    text,
+    content = 'daily_digests',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER daily_digests_fts_insert AFTER INSERT ON daily_digests BEGIN
+    INSERT INTO daily_digests_fts (rowid, text) VALUES (new.id, new.text);
+END;
+
+CREATE TRIGGER daily_digests_fts_delete AFTER DELETE ON daily_digests BEGIN
+    INSERT INTO daily_digests_fts (daily_digests_fts, rowid, text)
+    VALUES ('delete', old.id, old.text);
+END;
+
+CREATE TRIGGER daily_digests_fts_update AFTER UPDATE OF text ON daily_digests BEGIN
+    INSERT INTO daily_digests_fts (daily_digests_fts, rowid, text)
+    VALUES ('delete', old.id, old.text);
+    INSERT INTO daily_digests_fts (rowid, text) VALUES (new.id, new.text);
+END;
+
+-- Index everything stored before this migration.
+INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+INSERT INTO summaries_fts (summaries_fts) VALUES ('rebuild');
+INSERT INTO daily_digests_fts (daily_digests_fts) VALUES ('rebuild');
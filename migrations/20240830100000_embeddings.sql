@@ -0,0 +1,15 @@
+-- Embedding vectors of messages and summaries, as little-endian f32s. Vectors from different
+-- models can't be compared, so each is stored with the model that made it.
+CREATE TABLE message_embeddings (
+    message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    vector BLOB NOT NULL,
+    PRIMARY KEY (message_id, model)
+);
+
+CREATE TABLE summary_embeddings (
+    summary_id INTEGER NOT NULL REFERENCES summaries (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    vector BLOB NOT NULL,
+    PRIMARY KEY (summary_id, model)
+);
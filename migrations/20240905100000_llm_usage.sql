@@ -0,0 +1,18 @@
+-- What the LLM billed for each summary and digest, possibly over several requests. Rows made
+-- before this was recorded have NULLs.
+ALTER TABLE summaries ADD COLUMN model TEXT;
+ALTER TABLE summaries ADD COLUMN prompt_tokens INTEGER;
+ALTER TABLE summaries ADD COLUMN completion_tokens INTEGER;
+ALTER TABLE summaries ADD COLUMN latency_ms INTEGER;
+-- Estimated in dollars from the configured prices; NULL if the model has no price.
+ALTER TABLE summaries ADD COLUMN cost REAL;
+
+ALTER TABLE daily_digests ADD COLUMN model TEXT;
+ALTER TABLE daily_digests ADD COLUMN prompt_tokens INTEGER;
+ALTER TABLE daily_digests ADD COLUMN completion_tokens INTEGER;
+ALTER TABLE daily_digests ADD COLUMN latency_ms INTEGER;
+ALTER TABLE daily_digests ADD COLUMN cost REAL;
+
+-- For totalling this month's spend against the budget.
+CREATE INDEX summaries_timestamp ON summaries (timestamp);
+CREATE INDEX daily_digests_timestamp ON daily_digests (timestamp);
@@ -0,0 +1,7 @@
+-- When a message was deleted on Discord. Deleted messages keep their row, so the summaries they
+-- were in still know them, but lose their content and are left out of new summaries.
+ALTER TABLE messages ADD COLUMN deleted_at DATETIME;
+
+-- Set when a message a summary was made from is deleted afterwards, as the summary may still
+-- repeat what it said.
+ALTER TABLE summaries ADD COLUMN includes_deleted_content BOOLEAN NOT NULL DEFAULT FALSE;
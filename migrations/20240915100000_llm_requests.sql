@@ -0,0 +1,24 @@
+-- What the LLM billed for each request, whatever it was for and whether or not what it was part
+-- of succeeded in the end. The monthly budget is totalled from here.
+CREATE TABLE llm_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    -- Estimated in dollars from the configured prices; NULL if the model has no price.
+    cost REAL
+);
+
+CREATE INDEX llm_usage_timestamp ON llm_usage (timestamp);
+
+-- Carry over what summaries and digests recorded before, so that this month's total stays right.
+INSERT INTO llm_usage (timestamp, model, prompt_tokens, completion_tokens, latency_ms, cost)
+SELECT timestamp, model, COALESCE(prompt_tokens, 0), COALESCE(completion_tokens, 0),
+    COALESCE(latency_ms, 0), cost
+FROM summaries WHERE model IS NOT NULL
+UNION ALL
+SELECT timestamp, model, COALESCE(prompt_tokens, 0), COALESCE(completion_tokens, 0),
+    COALESCE(latency_ms, 0), cost
+FROM daily_digests WHERE model IS NOT NULL;
@@ -0,0 +1,11 @@
+-- When each scheduled digest run was due, whether or not it produced a digest, so that the
+-- schedule carries on from the last run after a restart.
+CREATE TABLE digest_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    scheduled_at DATETIME NOT NULL,
+    completed_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Until now the last digest stood in for the last run.
+INSERT INTO digest_runs (scheduled_at, completed_at)
+SELECT MAX(timestamp), MAX(timestamp) FROM daily_digests HAVING MAX(timestamp) IS NOT NULL;
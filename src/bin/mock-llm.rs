@@ -0,0 +1,34 @@
+//! A stand-in for the OpenAI and Anthropic chat APIs that answers every request with a canned
+//! response. Point `[summary] base_url` at it to run the whole pipeline without network access.
+use clap::Parser;
+use daily_discord_summarizer::mock_llm;
+use tracing::info;
+
+#[derive(Parser, Debug)]
+#[command(about = "Serve canned chat completion responses")]
+struct Args {
+    /// Host to bind to
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Port to bind to
+    #[arg(long, default_value_t = 8089)]
+    port: u16,
+    /// Canned response to return. Repeat to cycle through several responses in order.
+    #[arg(long = "response")]
+    responses: Vec<String>,
+    /// Answer the first N requests with `429 Too Many Requests` to exercise retries.
+    #[arg(long, default_value_t = 0)]
+    rate_limit_first: usize,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let app = mock_llm::router(args.responses, args.rate_limit_first);
+    let listener = tokio::net::TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
+    info!("Serving mock LLM API on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use eyre::{eyre, WrapErr};
+use serde::Serialize;
+use serenity::http::Http;
+use sqlx::SqlitePool;
+use tracing::info;
+
+use crate::config::{AppConfig, Scope};
+use crate::db;
+use crate::gpt::{self, MapReduceSummarizer};
+use crate::http_api::auth;
+use crate::services::commands::recap::parse_since;
+use crate::services::digests::{DailyRecapService, DigestTrigger};
+use crate::services::discord_poster::DigestPoster;
+use crate::services::embeddings::EmbeddingService;
+use crate::services::schedule::DigestSchedule;
+use crate::services::summarizer::SummarizerService;
+
+#[derive(Parser)]
+#[command(version, about = "Summarizes Discord channels into daily digests")]
+pub struct Cli {
+    /// What to do; runs the bot when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the bot: log messages from Discord, summarize them, produce digests and serve the API.
+    Serve,
+    /// Summarize a text file, one message per line, and print the summary.
+    SummarizeFile { path: PathBuf },
+    /// Produce digests of all summaries not yet in one, posting them if digest channels are
+    /// configured and DISCORD_BOT_SECRET is set.
+    DigestNow,
+    /// Summarize a channel's logged messages since some time and print the recap.
+    Recap {
+        /// Channel id to recap.
+        #[arg(long)]
+        channel: i64,
+        /// `last_day`, `last_week`, `last_month`, a duration like `3 days`, or a date.
+        #[arg(long, default_value = "last_day")]
+        since: String,
+    },
+    /// Write all summaries and digests as JSON.
+    Export {
+        /// File to write to instead of stdout.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Manage the database.
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Summarize logged messages that were never summarized, then exit.
+    Replay {
+        /// Only queue messages from this channel id.
+        #[arg(long)]
+        channel: Option<i64>,
+    },
+    /// Embed the messages and summaries that have no embedding yet, then exit.
+    Embed,
+    /// Manage HTTP API tokens.
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Apply any pending migrations.
+    Migrate,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Create a token in the database and print it. It can't be shown again.
+    Create {
+        /// What the token is for.
+        #[arg(long)]
+        name: String,
+        /// Scope to grant, repeatable; `read` if none is given.
+        #[arg(long = "scope", value_enum)]
+        scopes: Vec<Scope>,
+    },
+    /// List the tokens in the database.
+    List,
+    /// Revoke a token in the database.
+    Revoke { id: i64 },
+    /// Print the hash of a token, for the `[api]` section of the config.
+    Hash { token: String },
+}
+
+pub async fn summarize_file(config: &AppConfig, path: &Path) -> eyre::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+    let lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+
+    // Nothing is stored, so there is nowhere to record usage either.
+    let summarizer = MapReduceSummarizer::from_config(config, None)?;
+    let summary = summarizer.summarize(&config.summary.prompt, &lines).await?;
+    println!("{}", summary.text);
+    Ok(())
+}
+
+pub async fn digest_now(config: &AppConfig, db: Arc<SqlitePool>) -> eyre::Result<()> {
+    let poster = env::var("DISCORD_BOT_SECRET")
+        .ok()
+        .and_then(|token| DigestPoster::from_config(Arc::new(Http::new(&token)), &config.discord));
+
+    // Nothing can trigger a one-off run; it produces digests once and exits.
+    let (_, trigger_rx) = DigestTrigger::channel();
+    let summarizer = MapReduceSummarizer::from_config(config, Some(db.clone()))?;
+    let recap_srv = DailyRecapService::new(
+        db,
+        DigestSchedule::from_config(&config.service)?,
+        trigger_rx,
+        config.clone(),
+        Arc::new(summarizer),
+        poster,
+    );
+    recap_srv.produce_digests().await;
+    Ok(())
+}
+
+pub async fn recap(
+    config: &AppConfig,
+    db: Arc<SqlitePool>,
+    channel_id: i64,
+    since: &str,
+) -> eyre::Result<()> {
+    let since = parse_since(since).ok_or_else(|| eyre!("Could not understand --since {since}"))?;
+    let messages = db::fetch_channel_messages_since(&db, channel_id, since.naive_utc()).await?;
+    if messages.is_empty() {
+        info!("No messages in channel {channel_id} since {since}");
+        return Ok(());
+    }
+
+    let lines: Vec<String> = messages.iter().map(|m| m.prompt_line()).collect();
+    let summarizer = MapReduceSummarizer::from_config(config, Some(db.clone()))?;
+    let recap = summarizer.summarize(&config.summary.prompt, &lines).await?;
+    println!("{}", recap.text);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Export {
+    summaries: Vec<db::Summary>,
+    daily_digests: Vec<db::DailyDigest>,
+}
+
+pub async fn export(db: Arc<SqlitePool>, output: Option<&Path>) -> eyre::Result<()> {
+    let export = Export {
+        summaries: db::fetch_summaries(&db).await?,
+        daily_digests: db::fetch_daily_digests(&db).await?,
+    };
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path).wrap_err_with(|| format!("Could not create {}", path.display()))?,
+        ),
+        None => Box::new(io::stdout().lock()),
+    };
+    serde_json::to_writer_pretty(&mut writer, &export)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+pub async fn replay(
+    config: &AppConfig,
+    db: Arc<SqlitePool>,
+    channel_id: Option<i64>,
+) -> eyre::Result<()> {
+    // Nothing sends requests to a one-off run; it only works through what is already logged.
+    let (_, summarize_rx) = tokio::sync::mpsc::channel(1);
+    let summarizer = MapReduceSummarizer::from_config(config, Some(db.clone()))?;
+    let summary_srv =
+        SummarizerService::from_config(summarize_rx, db, Arc::new(summarizer), config);
+    summary_srv.replay(channel_id).await;
+    Ok(())
+}
+
+pub async fn embed(config: &AppConfig, db: Arc<SqlitePool>) -> eyre::Result<()> {
+    let (Some(embedder), Some(embeddings_config)) = (
+        gpt::embedding_provider_from_config(config, Some(db.clone()))?,
+        &config.embeddings,
+    ) else {
+        return Err(eyre!("No [embeddings] section in the config"));
+    };
+    let embedding_srv = EmbeddingService::from_config(db, embedder, embeddings_config);
+    let embedded = embedding_srv.embed_pending().await;
+    info!("Embedded {embedded} messages and summaries");
+    Ok(())
+}
+
+pub async fn token(config: &AppConfig, command: TokenCommand) -> eyre::Result<()> {
+    match command {
+        TokenCommand::Create { name, mut scopes } => {
+            let db = crate::connect_database(config).await;
+            if scopes.is_empty() {
+                scopes.push(Scope::Read);
+            }
+            scopes.sort();
+            scopes.dedup();
+
+            let token = auth::generate_token();
+            let scopes = auth::join_scopes(&scopes);
+            let id = db::insert_api_token(&db, &name, &auth::hash_token(&token), &scopes).await?;
+            info!("Created API token {id} ({name}) with scopes {scopes}");
+            println!("{token}");
+        }
+        TokenCommand::List => {
+            let db = crate::connect_database(config).await;
+            for token in db::fetch_api_tokens(&db).await? {
+                let status = match (token.revoked_at, token.last_used_at) {
+                    (Some(revoked_at), _) => format!("revoked {revoked_at}"),
+                    (None, Some(last_used_at)) => format!("last used {last_used_at}"),
+                    (None, None) => "never used".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\tcreated {}, {status}",
+                    token.id, token.name, token.scopes, token.created_at
+                );
+            }
+        }
+        TokenCommand::Revoke { id } => {
+            let db = crate::connect_database(config).await;
+            if !db::revoke_api_token(&db, id).await? {
+                return Err(eyre!("No unrevoked API token with id {id}"));
+            }
+            info!("Revoked API token {id}");
+        }
+        // Needs no database, so it works before one is set up.
+        TokenCommand::Hash { token } => println!("{}", auth::hash_token(&token)),
+    }
+    Ok(())
+}
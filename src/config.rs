@@ -1,6 +1,6 @@
 use config::{Config, ConfigError};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::collections::HashMap;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct AppConfig {
@@ -9,6 +9,11 @@ pub struct AppConfig {
     #[allow(unused)]
     pub discord: DiscordConfig,
     pub summary: SummaryConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    /// Semantic search is off unless this section is present.
+    #[serde(default)]
+    pub embeddings: Option<EmbeddingsConfig>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -18,24 +23,262 @@ pub struct DatabaseConfig {
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ServiceConfig {
+    /// Fallback digest interval, used when `digest_schedule` is not set.
+    #[serde(default = "default_produce_digest_interval_seconds")]
     pub produce_digest_interval_seconds: u64,
-    pub message_log_directory: PathBuf,
+    /// Cron expression or `daily at HH:MM [timezone]` for producing digests.
+    #[serde(default)]
+    pub digest_schedule: Option<String>,
+    /// IANA timezone the digest schedule is evaluated in, e.g. `America/Los_Angeles`.
+    #[serde(default)]
+    pub digest_timezone: Option<String>,
+    /// Also combine each run's per-channel digests into one roll-up digest per guild.
+    #[serde(default)]
+    pub digest_rollup: bool,
     pub port: u16,
     pub host: String,
     pub max_gpt_request_tokens: usize,
+    /// How many times a batch of messages is attempted before its summarize job is marked failed.
+    #[serde(default = "default_summarize_job_max_attempts")]
+    pub summarize_job_max_attempts: u32,
+    /// How long to wait before retrying a failed summarize job.
+    #[serde(default = "default_summarize_job_retry_seconds")]
+    pub summarize_job_retry_seconds: u64,
+    /// How many times a crashed background service is restarted within
+    /// `service_restart_window_seconds` before the whole process exits.
+    #[serde(default = "default_max_service_restarts")]
+    pub max_service_restarts: u32,
+    #[serde(default = "default_service_restart_window_seconds")]
+    pub service_restart_window_seconds: u64,
+}
+
+fn default_produce_digest_interval_seconds() -> u64 {
+    10800
+}
+
+fn default_summarize_job_max_attempts() -> u32 {
+    5
+}
+
+fn default_summarize_job_retry_seconds() -> u64 {
+    300
+}
+
+fn default_max_service_restarts() -> u32 {
+    5
+}
+
+fn default_service_restart_window_seconds() -> u64 {
+    600
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct DiscordConfig {
     #[allow(unused)]
     pub channel_ids: Vec<String>,
+    /// Where to post digests of channels without their own entry in `digest_channel_ids`, and
+    /// roll-up digests.
+    #[serde(default)]
+    pub digest_channel_id: Option<String>,
+    /// Where to post each source channel's digests, keyed by source channel id.
+    #[serde(default)]
+    pub digest_channel_ids: HashMap<String, String>,
+    /// Post digests as embeds rather than plain messages.
+    #[serde(default)]
+    pub digest_embeds: bool,
+    /// Where to tell admins about things that need their attention, like an exhausted budget.
+    #[serde(default)]
+    pub admin_channel_id: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    OpenAi,
+    Anthropic,
+    Ollama,
+    Echo,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct SummaryConfig {
+    #[serde(default)]
+    pub provider: ProviderKind,
     pub model: String,
     pub prompt: String,
+    /// Instructions for answering `/ask` questions from numbered sources.
+    #[serde(default = "default_ask_prompt")]
+    pub ask_prompt: String,
     pub max_tokens: usize,
+    /// How many chunks of an oversized input are summarized at once.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Overrides the provider's default API base URL, e.g. to point at a local mock server.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Tiktoken encoding used to count tokens, e.g. `cl100k_base`. Defaults to the model's own.
+    #[serde(default)]
+    pub tokenizer: Option<String>,
+    /// Canned response returned by the `echo` provider.
+    #[serde(default)]
+    pub fixture: Option<String>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Prices of models by name, to estimate what summaries, digests and embeddings cost.
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    /// Dollars LLM requests, embeddings included, may cost per calendar month (UTC) before
+    /// automatic summarization pauses until the next month.
+    #[serde(default)]
+    pub monthly_budget: Option<f64>,
+}
+
+/// Dollars per million tokens.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+fn default_max_concurrent_requests() -> usize {
+    4
+}
+
+fn default_ask_prompt() -> String {
+    "You answer questions about a chat server between a group of friends, using only the \
+     numbered sources from its history that follow the question. Cite the sources you rely on \
+     by number, like [1]. If the sources don't answer the question, say so instead of guessing. \
+     You must only use names or 'they / them' pronouns. Be brief."
+        .to_string()
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingProviderKind {
+    OpenAi,
+    Ollama,
+    /// Local and deterministic, for tests and offline use; it only matches shared words.
+    Hashing,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmbeddingsConfig {
+    pub provider: EmbeddingProviderKind,
+    /// Embedding model, e.g. `text-embedding-3-small`. Unused by the `hashing` provider.
+    #[serde(default)]
+    pub model: String,
+    /// Overrides the provider's default API base URL.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Vector size of the `hashing` provider.
+    #[serde(default = "default_embedding_dimensions")]
+    pub dimensions: usize,
+    /// How many texts are embedded per request.
+    #[serde(default = "default_embedding_batch_size")]
+    pub batch_size: usize,
+    /// How often new messages and summaries are embedded.
+    #[serde(default = "default_embedding_interval_seconds")]
+    pub interval_seconds: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+fn default_embedding_dimensions() -> usize {
+    256
+}
+
+fn default_embedding_batch_size() -> usize {
+    64
+}
+
+fn default_embedding_interval_seconds() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total attempts per request, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    pub request_timeout_seconds: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            multiplier: 2.0,
+            request_timeout_seconds: 120,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ApiConfig {
+    /// Scopes granted to requests without a token. Empty, the default, requires a token for
+    /// every route but the `/healthz` and `/readyz` health checks, which never need one.
+    #[serde(default)]
+    pub anonymous_scopes: Vec<Scope>,
+    /// Tokens accepted in addition to those stored in the database.
+    #[serde(default)]
+    pub tokens: Vec<ApiTokenConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ApiTokenConfig {
+    pub name: String,
+    /// Hex-encoded SHA-256 of the token, as printed by `daily-discord-summarizer token hash`.
+    pub sha256: String,
+    #[serde(default = "default_token_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+fn default_token_scopes() -> Vec<Scope> {
+    vec![Scope::Read]
+}
+
+/// What an API token may do.
+#[derive(
+    Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read summaries and digests.
+    Read,
+    /// Start jobs, like producing digests.
+    Trigger,
+    /// Everything.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Trigger => "trigger",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "trigger" => Some(Scope::Trigger),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    /// Whether holding this scope grants `needed`.
+    pub fn grants(&self, needed: Scope) -> bool {
+        *self == Scope::Admin || *self == needed
+    }
 }
 
 impl AppConfig {
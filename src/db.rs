@@ -1,23 +1,93 @@
 use chrono::NaiveDateTime;
-use futures::stream::StreamExt;
-use futures::stream::{self};
 use serde::{Deserialize, Serialize};
-use sqlx::{Error, SqlitePool};
-use std::sync::Arc;
+use sqlx::{Error, QueryBuilder, Sqlite, SqlitePool};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct Summary {
     pub id: i64,
     pub daily_digest_id: Option<i64>,
     pub text: String,
     pub timestamp: NaiveDateTime,
+    pub channel_id: Option<i64>,
+    pub guild_id: Option<i64>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    pub latency_ms: Option<i64>,
+    pub cost: Option<f64>,
+    /// Whether a message the summary was made from was deleted afterwards.
+    pub includes_deleted_content: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct DailyDigestData {
+/// What the LLM billed for a request, or for all the requests producing a summary or digest took.
+#[derive(Clone, Debug)]
+pub struct LlmUsage {
+    pub model: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub latency_ms: i64,
+    /// In dollars, if the model has a configured price.
+    pub cost: Option<f64>,
+}
+
+/// What the LLM billed for one model's requests over some period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelUsage {
+    pub model: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    /// In dollars, for the requests made while the model had a configured price.
+    pub cost: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredMessage {
     pub id: i64,
-    pub text: String,
+    pub channel_id: i64,
+    pub guild_id: Option<i64>,
+    pub author_id: i64,
+    pub display_name: String,
+    pub content: String,
     pub timestamp: NaiveDateTime,
+    pub reply_to: Option<i64>,
+    pub edited_at: Option<NaiveDateTime>,
+    pub summary_id: Option<i64>,
+    pub job_id: Option<i64>,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+impl StoredMessage {
+    /// The message as a single line of summarizer input.
+    pub fn prompt_line(&self) -> String {
+        format!(
+            "timestamp: {}, author: {}, content: {}",
+            self.timestamp, self.display_name, self.content
+        )
+    }
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SummarizeJob {
+    pub id: i64,
+    pub status: JobStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub summary_id: Option<i64>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub channel_id: Option<i64>,
+    pub guild_id: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,74 +95,632 @@ pub struct DailyDigest {
     pub id: i64,
     pub text: String,
     pub timestamp: NaiveDateTime,
+    pub channel_id: Option<i64>,
+    pub guild_id: Option<i64>,
+    pub rollup: bool,
+    pub discord_message_id: Option<i64>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    pub latency_ms: Option<i64>,
+    pub cost: Option<f64>,
     pub summaries: Vec<Summary>,
 }
 
-pub async fn fetch_summaries(pool: Arc<SqlitePool>) -> Vec<Summary> {
+/// Which rows of a list endpoint to fetch. Rows are ordered by id, which follows the order they
+/// were created in, and `cursor` is the id of the last row already seen.
+pub struct ListFilter {
+    pub channel_id: Option<i64>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub cursor: Option<i64>,
+    pub ascending: bool,
+    pub limit: i64,
+}
+
+impl ListFilter {
+    /// Appends the filter's WHERE, ORDER BY and LIMIT clauses to a query over a table with `id`,
+    /// `timestamp` and `channel_id` columns.
+    fn push_clauses(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        query.push(" WHERE 1 = 1");
+        if let Some(channel_id) = self.channel_id {
+            query.push(" AND channel_id = ").push_bind(channel_id);
+        }
+        if let Some(since) = self.since {
+            query.push(" AND timestamp >= ").push_bind(since);
+        }
+        if let Some(until) = self.until {
+            query.push(" AND timestamp < ").push_bind(until);
+        }
+        if let Some(cursor) = self.cursor {
+            query
+                .push(if self.ascending {
+                    " AND id > "
+                } else {
+                    " AND id < "
+                })
+                .push_bind(cursor);
+        }
+        query
+            .push(if self.ascending {
+                " ORDER BY id ASC"
+            } else {
+                " ORDER BY id DESC"
+            })
+            .push(" LIMIT ")
+            .push_bind(self.limit);
+    }
+}
+
+pub async fn fetch_summaries(pool: &SqlitePool) -> Result<Vec<Summary>, Error> {
     sqlx::query_as!(Summary, "SELECT * FROM summaries")
-        .fetch_all(&*pool)
+        .fetch_all(pool)
         .await
-        .unwrap_or_else(|_| vec![])
 }
 
-pub async fn insert_summary(pool: &SqlitePool, text: &str) -> Result<i64, Error> {
-    let result = sqlx::query!(
-        "INSERT INTO summaries (daily_digest_id, text) VALUES (?, ?)",
-        None::<i64>,
-        text
+pub async fn insert_message(pool: &SqlitePool, message: &StoredMessage) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT OR IGNORE INTO messages
+            (id, channel_id, guild_id, author_id, display_name, content, timestamp, reply_to, edited_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        message.id,
+        message.channel_id,
+        message.guild_id,
+        message.author_id,
+        message.display_name,
+        message.content,
+        message.timestamp,
+        message.reply_to,
+        message.edited_at,
     )
     .execute(pool)
     .await?;
 
-    Ok(result.last_insert_rowid())
+    Ok(())
 }
 
-pub async fn fetch_daily_digests(pool: Arc<SqlitePool>) -> Vec<DailyDigest> {
-    let digests = sqlx::query_as!(
-        DailyDigestData,
-        "SELECT id, text, timestamp FROM daily_digests"
+/// Replaces the content of a message that was edited, unless it was deleted. Its embeddings are
+/// dropped so that it is embedded again. Returns whether the message is stored.
+pub async fn update_message_content(
+    pool: &SqlitePool,
+    id: i64,
+    content: &str,
+    edited_at: Option<NaiveDateTime>,
+) -> Result<bool, Error> {
+    let mut transaction = pool.begin().await?;
+
+    let updated = sqlx::query!(
+        "UPDATE messages SET content = ?, edited_at = COALESCE(?, edited_at)
+        WHERE id = ? AND deleted_at IS NULL",
+        content,
+        edited_at,
+        id
     )
-    .fetch_all(&*pool)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    sqlx::query!("DELETE FROM message_embeddings WHERE message_id = ?", id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(updated > 0)
+}
+
+/// Tombstones deleted messages: their content and embeddings are dropped, and the summaries
+/// already made from them are flagged. Returns how many of them were stored.
+pub async fn delete_messages(pool: &SqlitePool, ids: &[i64]) -> Result<u64, Error> {
+    let mut transaction = pool.begin().await?;
+
+    let mut deleted = 0;
+    for id in ids {
+        deleted += sqlx::query!(
+            "UPDATE messages SET content = '', deleted_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND deleted_at IS NULL",
+            id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        sqlx::query!(
+            "UPDATE summaries SET includes_deleted_content = TRUE
+            WHERE id = (SELECT summary_id FROM messages WHERE id = ?)",
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        sqlx::query!("DELETE FROM message_embeddings WHERE message_id = ?", id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(deleted)
+}
+
+/// All messages not yet included in a summary or queued for one, oldest first.
+pub async fn fetch_unqueued_messages(pool: &SqlitePool) -> Result<Vec<StoredMessage>, Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        "SELECT * FROM messages
+        WHERE summary_id IS NULL AND job_id IS NULL AND deleted_at IS NULL
+        ORDER BY timestamp ASC, id ASC"
+    )
+    .fetch_all(pool)
     .await
-    .unwrap_or_else(|_| vec![]);
-
-    stream::iter(digests)
-        .then(|digest| {
-            let pool_clone = pool.clone();
-            async move {
-                let summaries = sqlx::query_as!(
-                    Summary,
-                    "SELECT * FROM summaries WHERE daily_digest_id = ?",
-                    digest.id
-                )
-                .fetch_all(&*pool_clone)
-                .await
-                .unwrap_or_else(|_| vec![]);
-
-                DailyDigest {
-                    id: digest.id,
-                    text: digest.text,
-                    timestamp: digest.timestamp,
-                    summaries,
-                }
-            }
-        })
-        .collect::<Vec<DailyDigest>>()
+}
+
+pub async fn fetch_unqueued_channel_messages(
+    pool: &SqlitePool,
+    channel_id: i64,
+) -> Result<Vec<StoredMessage>, Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        "SELECT * FROM messages
+        WHERE summary_id IS NULL AND job_id IS NULL AND deleted_at IS NULL AND channel_id = ?
+        ORDER BY timestamp ASC, id ASC",
+        channel_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn fetch_job_messages(
+    pool: &SqlitePool,
+    job_id: i64,
+) -> Result<Vec<StoredMessage>, Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        "SELECT * FROM messages
+        WHERE job_id = ? AND deleted_at IS NULL
+        ORDER BY timestamp ASC, id ASC",
+        job_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// A channel's messages from `since` onwards, oldest first, whether summarized or not, leaving
+/// out deleted ones.
+pub async fn fetch_channel_messages_since(
+    pool: &SqlitePool,
+    channel_id: i64,
+    since: NaiveDateTime,
+) -> Result<Vec<StoredMessage>, Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        "SELECT * FROM messages
+        WHERE channel_id = ? AND timestamp >= ? AND deleted_at IS NULL
+        ORDER BY timestamp ASC, id ASC",
+        channel_id,
+        since
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn fetch_message(pool: &SqlitePool, id: i64) -> Result<Option<StoredMessage>, Error> {
+    sqlx::query_as!(StoredMessage, "SELECT * FROM messages WHERE id = ?", id)
+        .fetch_optional(pool)
         .await
 }
 
+/// Queues the given messages, all from one channel, to be summarized together.
+pub async fn insert_summarize_job(
+    pool: &SqlitePool,
+    channel_id: i64,
+    guild_id: Option<i64>,
+    message_ids: &[i64],
+) -> Result<i64, Error> {
+    let mut transaction = pool.begin().await?;
+
+    let job_id = sqlx::query!(
+        "INSERT INTO summarize_jobs (channel_id, guild_id) VALUES (?, ?)",
+        channel_id,
+        guild_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .last_insert_rowid();
+
+    for message_id in message_ids {
+        sqlx::query!(
+            "UPDATE messages SET job_id = ? WHERE id = ?",
+            job_id,
+            message_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(job_id)
+}
+
+/// Marks a job done without a summary, for when all of its messages were deleted.
+pub async fn complete_empty_summarize_job(pool: &SqlitePool, job_id: i64) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE summarize_jobs
+        SET status = 'done', last_error = NULL, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?",
+        job_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Puts jobs that were interrupted mid-summary back in the queue. Returns how many there were.
+pub async fn requeue_in_progress_jobs(pool: &SqlitePool) -> Result<u64, Error> {
+    let result = sqlx::query!(
+        "UPDATE summarize_jobs
+        SET status = 'pending', next_attempt_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+        WHERE status = 'in_progress'"
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Makes the failed jobs, and those waiting to be retried, due now with all their attempts
+/// ahead of them. Only those of `channel_id`, if given. Returns how many there were.
+pub async fn retry_summarize_jobs(
+    pool: &SqlitePool,
+    channel_id: Option<i64>,
+) -> Result<u64, Error> {
+    let result = sqlx::query!(
+        "UPDATE summarize_jobs
+        SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE (status = 'failed' OR (status = 'pending' AND next_attempt_at > CURRENT_TIMESTAMP))
+            AND (?1 IS NULL OR channel_id = ?1)",
+        channel_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Takes the oldest pending job that is due, of `channel_id` if given, marking it in progress.
+pub async fn claim_next_summarize_job(
+    pool: &SqlitePool,
+    channel_id: Option<i64>,
+) -> Result<Option<SummarizeJob>, Error> {
+    sqlx::query_as!(
+        SummarizeJob,
+        r#"UPDATE summarize_jobs
+        SET status = 'in_progress', attempts = attempts + 1, updated_at = CURRENT_TIMESTAMP
+        WHERE id = (
+            SELECT id FROM summarize_jobs
+            WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+                AND (?1 IS NULL OR channel_id = ?1)
+            ORDER BY id ASC
+            LIMIT 1
+        )
+        RETURNING id AS "id!", status AS "status!: JobStatus", attempts AS "attempts!",
+            last_error, summary_id, next_attempt_at AS "next_attempt_at!",
+            created_at AS "created_at!", updated_at AS "updated_at!", channel_id, guild_id"#,
+        channel_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Stores the summary of a job's messages, marking the messages as summarized and the job done.
+pub async fn complete_summarize_job(
+    pool: &SqlitePool,
+    job_id: i64,
+    text: &str,
+    usage: &LlmUsage,
+) -> Result<i64, Error> {
+    let mut transaction = pool.begin().await?;
+
+    // The summary covers the same channel as the job it came from.
+    let summary_id = sqlx::query!(
+        "INSERT INTO summaries (daily_digest_id, text, channel_id, guild_id, model, prompt_tokens,
+            completion_tokens, latency_ms, cost)
+        SELECT NULL, ?, channel_id, guild_id, ?, ?, ?, ?, ? FROM summarize_jobs WHERE id = ?",
+        text,
+        usage.model,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.latency_ms,
+        usage.cost,
+        job_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .last_insert_rowid();
+
+    sqlx::query!(
+        "UPDATE messages SET summary_id = ? WHERE job_id = ?",
+        summary_id,
+        job_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    // Messages deleted while they were being summarized made it into the summary anyway. The
+    // job's `updated_at` is when it was claimed, and so when its messages were read.
+    sqlx::query!(
+        "UPDATE summaries SET includes_deleted_content = TRUE
+        WHERE id = ? AND EXISTS (
+            SELECT 1 FROM messages m JOIN summarize_jobs j ON j.id = m.job_id
+            WHERE m.job_id = ? AND m.deleted_at >= j.updated_at
+        )",
+        summary_id,
+        job_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE summarize_jobs
+        SET status = 'done', summary_id = ?, last_error = NULL, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?",
+        summary_id,
+        job_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(summary_id)
+}
+
+/// Records a failed attempt at a job. It is retried after `retry_seconds`, unless it has used
+/// up its `max_attempts`, in which case it is marked failed for good.
+pub async fn fail_summarize_job(
+    pool: &SqlitePool,
+    job_id: i64,
+    error: &str,
+    max_attempts: i64,
+    retry_seconds: i64,
+) -> Result<JobStatus, Error> {
+    let retry_modifier = format!("+{retry_seconds} seconds");
+    let status = sqlx::query_scalar!(
+        r#"UPDATE summarize_jobs
+        SET status = CASE WHEN attempts >= ? THEN 'failed' ELSE 'pending' END,
+            last_error = ?,
+            next_attempt_at = datetime('now', ?),
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        RETURNING status AS "status!: JobStatus""#,
+        max_attempts,
+        error,
+        retry_modifier,
+        job_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(status)
+}
+
+/// Every digest, oldest first, each with the summaries it was made from.
+pub async fn fetch_daily_digests(pool: &SqlitePool) -> Result<Vec<DailyDigest>, Error> {
+    fetch_digests_with_summaries(pool, true, |_| {}).await
+}
+
+pub async fn fetch_daily_digest(pool: &SqlitePool, id: i64) -> Result<Option<DailyDigest>, Error> {
+    let mut digests = fetch_digests_with_summaries(pool, true, |query| {
+        query.push(" WHERE id = ").push_bind(id);
+    })
+    .await?;
+    Ok(digests.pop())
+}
+
+/// The most recent digest, or the most recent one of `channel_id` if given.
+pub async fn fetch_latest_daily_digest(
+    pool: &SqlitePool,
+    channel_id: Option<i64>,
+) -> Result<Option<DailyDigest>, Error> {
+    let mut digests = fetch_digests_with_summaries(pool, false, |query| {
+        if let Some(channel_id) = channel_id {
+            query.push(" WHERE channel_id = ").push_bind(channel_id);
+        }
+        query.push(" ORDER BY id DESC LIMIT 1");
+    })
+    .await?;
+    Ok(digests.pop())
+}
+
+pub async fn fetch_summary(pool: &SqlitePool, id: i64) -> Result<Option<Summary>, Error> {
+    sqlx::query_as!(
+        Summary,
+        r#"SELECT id AS "id!", daily_digest_id, text, timestamp, channel_id, guild_id, model,
+            prompt_tokens, completion_tokens, latency_ms, cost, includes_deleted_content
+        FROM summaries
+        WHERE id = ?"#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn fetch_summaries_page(
+    pool: &SqlitePool,
+    filter: &ListFilter,
+) -> Result<Vec<Summary>, Error> {
+    let mut query = QueryBuilder::new("SELECT * FROM summaries");
+    filter.push_clauses(&mut query);
+    query.build_query_as().fetch_all(pool).await
+}
+
+/// Digests matching `filter`, each with the summaries it was made from.
+pub async fn fetch_daily_digests_page(
+    pool: &SqlitePool,
+    filter: &ListFilter,
+) -> Result<Vec<DailyDigest>, Error> {
+    fetch_digests_with_summaries(pool, filter.ascending, |query| filter.push_clauses(query)).await
+}
+
+/// A digest joined with one of its summaries, or with none if it has no summaries.
+#[derive(sqlx::FromRow)]
+struct DigestSummaryRow {
+    id: i64,
+    text: String,
+    timestamp: NaiveDateTime,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    rollup: bool,
+    discord_message_id: Option<i64>,
+    model: Option<String>,
+    prompt_tokens: Option<i64>,
+    completion_tokens: Option<i64>,
+    latency_ms: Option<i64>,
+    cost: Option<f64>,
+    summary_id: Option<i64>,
+    summary_text: Option<String>,
+    summary_timestamp: Option<NaiveDateTime>,
+    summary_channel_id: Option<i64>,
+    summary_guild_id: Option<i64>,
+    summary_model: Option<String>,
+    summary_prompt_tokens: Option<i64>,
+    summary_completion_tokens: Option<i64>,
+    summary_latency_ms: Option<i64>,
+    summary_cost: Option<f64>,
+    summary_includes_deleted_content: Option<bool>,
+}
+
+/// Digests selected by the clauses `push_clauses` appends to a query over `daily_digests`, each
+/// with the summaries it was made from, in a single query. The clauses may limit the digests, so
+/// they are selected in a subquery that the summaries are joined to, and ordered by id again,
+/// ascending or not, since the subquery's order doesn't carry over to the join.
+async fn fetch_digests_with_summaries(
+    pool: &SqlitePool,
+    ascending: bool,
+    push_clauses: impl FnOnce(&mut QueryBuilder<'_, Sqlite>),
+) -> Result<Vec<DailyDigest>, Error> {
+    let mut query = QueryBuilder::new(
+        "SELECT d.id, d.text, d.timestamp, d.channel_id, d.guild_id, d.rollup,
+            d.discord_message_id, d.model, d.prompt_tokens, d.completion_tokens, d.latency_ms,
+            d.cost, s.id AS summary_id, s.text AS summary_text, s.timestamp AS summary_timestamp,
+            s.channel_id AS summary_channel_id, s.guild_id AS summary_guild_id,
+            s.model AS summary_model, s.prompt_tokens AS summary_prompt_tokens,
+            s.completion_tokens AS summary_completion_tokens,
+            s.latency_ms AS summary_latency_ms, s.cost AS summary_cost,
+            s.includes_deleted_content AS summary_includes_deleted_content
+        FROM (SELECT * FROM daily_digests",
+    );
+    push_clauses(&mut query);
+    query.push(
+        ") d
+        LEFT JOIN summaries s ON s.daily_digest_id = d.id",
+    );
+    query.push(if ascending {
+        " ORDER BY d.id ASC, s.id ASC"
+    } else {
+        " ORDER BY d.id DESC, s.id ASC"
+    });
+
+    let rows: Vec<DigestSummaryRow> = query.build_query_as().fetch_all(pool).await?;
+    Ok(digests_from_rows(rows))
+}
+
+/// Folds joined rows, ordered by digest, back into digests with their summaries.
+fn digests_from_rows(rows: Vec<DigestSummaryRow>) -> Vec<DailyDigest> {
+    let mut digests: Vec<DailyDigest> = vec![];
+    for row in rows {
+        if digests.last().map(|d| d.id) != Some(row.id) {
+            digests.push(DailyDigest {
+                id: row.id,
+                text: row.text,
+                timestamp: row.timestamp,
+                channel_id: row.channel_id,
+                guild_id: row.guild_id,
+                rollup: row.rollup,
+                discord_message_id: row.discord_message_id,
+                model: row.model,
+                prompt_tokens: row.prompt_tokens,
+                completion_tokens: row.completion_tokens,
+                latency_ms: row.latency_ms,
+                cost: row.cost,
+                summaries: vec![],
+            });
+        }
+        if let (Some(id), Some(text), Some(timestamp)) =
+            (row.summary_id, row.summary_text, row.summary_timestamp)
+        {
+            let digest = digests.last_mut().expect("digest was just pushed");
+            digest.summaries.push(Summary {
+                id,
+                daily_digest_id: Some(digest.id),
+                text,
+                timestamp,
+                channel_id: row.summary_channel_id,
+                guild_id: row.summary_guild_id,
+                model: row.summary_model,
+                prompt_tokens: row.summary_prompt_tokens,
+                completion_tokens: row.summary_completion_tokens,
+                latency_ms: row.summary_latency_ms,
+                cost: row.summary_cost,
+                includes_deleted_content: row.summary_includes_deleted_content.unwrap_or_default(),
+            });
+        }
+    }
+    digests
+}
+
+/// Summaries not yet included in a digest, grouped by the channel they cover, oldest first.
+/// Summaries from before per-channel tracking are grouped under `None`.
+pub async fn fetch_undigested_summaries_by_channel(
+    pool: &SqlitePool,
+) -> Result<Vec<(Option<i64>, Vec<Summary>)>, Error> {
+    let summaries = sqlx::query_as!(
+        Summary,
+        r#"SELECT id AS "id!", daily_digest_id, text, timestamp, channel_id, guild_id, model,
+            prompt_tokens, completion_tokens, latency_ms, cost, includes_deleted_content
+        FROM summaries
+        WHERE daily_digest_id IS NULL
+        ORDER BY channel_id ASC, timestamp ASC, id ASC"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut by_channel: Vec<(Option<i64>, Vec<Summary>)> = vec![];
+    for summary in summaries {
+        match by_channel.last_mut() {
+            Some((channel_id, group)) if *channel_id == summary.channel_id => group.push(summary),
+            _ => by_channel.push((summary.channel_id, vec![summary])),
+        }
+    }
+    Ok(by_channel)
+}
+
+/// Stores a digest of a channel's summaries, linking the summaries to it.
 pub async fn insert_daily_digest(
     pool: &SqlitePool,
     digest_text: String,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
     summary_ids: Vec<i64>,
-) -> Result<(), Error> {
+    usage: &LlmUsage,
+) -> Result<i64, Error> {
     let mut transaction = pool.begin().await?;
 
     // Insert the new digest and get its ID
-    let digest_id: i64 = sqlx::query!("INSERT INTO daily_digests (text) VALUES (?)", digest_text)
-        .execute(&mut *transaction)
-        .await?
-        .last_insert_rowid();
+    let digest_id: i64 = sqlx::query!(
+        "INSERT INTO daily_digests (text, channel_id, guild_id, model, prompt_tokens,
+            completion_tokens, latency_ms, cost)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        digest_text,
+        channel_id,
+        guild_id,
+        usage.model,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.latency_ms,
+        usage.cost
+    )
+    .execute(&mut *transaction)
+    .await?
+    .last_insert_rowid();
 
     // Update each summary to link it to the new digest
     for summary_id in summary_ids {
@@ -107,23 +735,985 @@ pub async fn insert_daily_digest(
 
     // Commit the transaction
     transaction.commit().await?;
+    Ok(digest_id)
+}
+
+/// Stores a digest combining the per-channel digests of a guild.
+pub async fn insert_rollup_digest(
+    pool: &SqlitePool,
+    digest_text: String,
+    guild_id: Option<i64>,
+    usage: &LlmUsage,
+) -> Result<i64, Error> {
+    let digest_id = sqlx::query!(
+        "INSERT INTO daily_digests (text, guild_id, rollup, model, prompt_tokens,
+            completion_tokens, latency_ms, cost)
+        VALUES (?, ?, TRUE, ?, ?, ?, ?, ?)",
+        digest_text,
+        guild_id,
+        usage.model,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.latency_ms,
+        usage.cost
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(digest_id)
+}
+
+/// When the last scheduled digest run was due.
+pub async fn fetch_last_digest_run(pool: &SqlitePool) -> Result<Option<NaiveDateTime>, Error> {
+    sqlx::query_scalar!("SELECT scheduled_at FROM digest_runs ORDER BY scheduled_at DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await
+}
+
+/// Records that the scheduled digest run due at `scheduled_at` is done.
+pub async fn insert_digest_run(
+    pool: &SqlitePool,
+    scheduled_at: NaiveDateTime,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO digest_runs (scheduled_at) VALUES (?)",
+        scheduled_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// When the most recent digest was produced, if there ever was one.
+pub async fn fetch_last_digest_timestamp(
+    pool: &SqlitePool,
+) -> Result<Option<NaiveDateTime>, Error> {
+    sqlx::query_scalar!("SELECT timestamp FROM daily_digests ORDER BY timestamp DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn fetch_last_summary_timestamp(
+    pool: &SqlitePool,
+) -> Result<Option<NaiveDateTime>, Error> {
+    sqlx::query_scalar!("SELECT timestamp FROM summaries ORDER BY timestamp DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await
+}
+
+/// The tokens billed for each model since `since`, and their cost as estimated when each request
+/// was made.
+pub async fn fetch_usage_by_model_since(
+    pool: &SqlitePool,
+    since: NaiveDateTime,
+) -> Result<Vec<ModelUsage>, Error> {
+    sqlx::query_as!(
+        ModelUsage,
+        r#"SELECT model, SUM(prompt_tokens) AS "prompt_tokens!: i64",
+            SUM(completion_tokens) AS "completion_tokens!: i64",
+            SUM(cost) AS "cost: f64"
+        FROM llm_usage WHERE timestamp >= ? GROUP BY model ORDER BY model"#,
+        since
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Records what the LLM billed for a single request.
+pub async fn insert_llm_usage(pool: &SqlitePool, usage: &LlmUsage) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO llm_usage (model, prompt_tokens, completion_tokens, latency_ms, cost)
+        VALUES (?, ?, ?, ?, ?)",
+        usage.model,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.latency_ms,
+        usage.cost
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// How many summarize jobs are waiting to run, including ones waiting to be retried.
+pub async fn count_pending_summarize_jobs(pool: &SqlitePool) -> Result<i64, Error> {
+    sqlx::query_scalar!("SELECT COUNT(*) FROM summarize_jobs WHERE status = 'pending'")
+        .fetch_one(pool)
+        .await
+        .map(i64::from)
+}
+
+/// Checks that the database answers queries.
+pub async fn ping(pool: &SqlitePool) -> Result<(), Error> {
+    sqlx::query("SELECT 1").execute(pool).await.map(|_| ())
+}
+
+pub async fn set_digest_discord_message_id(
+    pool: &SqlitePool,
+    digest_id: i64,
+    message_id: i64,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE daily_digests SET discord_message_id = ? WHERE id = ?",
+        message_id,
+        digest_id
+    )
+    .execute(pool)
+    .await?;
+
     Ok(())
 }
 
-// pub async fn fetch_latest_summaries(
-//     pool: Arc<SqlitePool>,
-//     count: usize,
-//     page: usize,
-// ) -> Vec<Summary> {
-//     let count = count as i64;
-//     let offset = count * (page - 1) as i64;
-//     sqlx::query_as!(
-//         Summary,
-//         "SELECT * FROM summaries ORDER BY timestamp DESC LIMIT ? OFFSET ?",
-//         count,
-//         offset
-//     )
-//     .fetch_all(&*pool)
-//     .await
-//     .unwrap_or_else(|_| vec![])
-// }
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+pub async fn insert_api_token(
+    pool: &SqlitePool,
+    name: &str,
+    token_hash: &str,
+    scopes: &str,
+) -> Result<i64, Error> {
+    let id = sqlx::query!(
+        "INSERT INTO api_tokens (name, token_hash, scopes) VALUES (?, ?, ?)",
+        name,
+        token_hash,
+        scopes
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn fetch_api_tokens(pool: &SqlitePool) -> Result<Vec<ApiToken>, Error> {
+    sqlx::query_as!(ApiToken, "SELECT * FROM api_tokens ORDER BY id ASC")
+        .fetch_all(pool)
+        .await
+}
+
+/// Looks up a token that has not been revoked by its hash.
+pub async fn fetch_api_token(
+    pool: &SqlitePool,
+    token_hash: &str,
+) -> Result<Option<ApiToken>, Error> {
+    sqlx::query_as!(
+        ApiToken,
+        "SELECT * FROM api_tokens WHERE token_hash = ? AND revoked_at IS NULL",
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Records that a token was used, unless that was already recorded within the last minute.
+/// Returns whether it was recorded.
+pub async fn touch_api_token(pool: &SqlitePool, id: i64) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND (last_used_at IS NULL OR last_used_at < datetime('now', '-1 minute'))",
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes a token, returning whether there was an unrevoked token with that id.
+pub async fn revoke_api_token(pool: &SqlitePool, id: i64) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "UPDATE api_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND revoked_at IS NULL",
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// A full-text search match, from a message, a summary or a digest.
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct SearchHit {
+    /// `message`, `summary` or `digest`.
+    pub kind: String,
+    pub id: i64,
+    pub channel_id: Option<i64>,
+    pub guild_id: Option<i64>,
+    pub timestamp: NaiveDateTime,
+    /// The matching part of the text, with the matched terms in markdown bold.
+    pub snippet: String,
+    /// BM25 score, or for semantic search the negated cosine similarity; lower is a better match.
+    pub rank: f64,
+    /// The Discord message matched, or for summaries and digests the first message they cover.
+    pub message_id: Option<i64>,
+}
+
+impl SearchHit {
+    /// A link that jumps to `message_id` in Discord.
+    pub fn jump_url(&self) -> Option<String> {
+        let message_id = self.message_id?;
+        let channel_id = self.channel_id?;
+        let guild = self
+            .guild_id
+            .map_or_else(|| "@me".to_string(), |id| id.to_string());
+        Some(format!(
+            "https://discord.com/channels/{guild}/{channel_id}/{message_id}"
+        ))
+    }
+}
+
+/// Turns free text into an FTS5 query of its words joined by `separator`, so that punctuation in
+/// it is never read as query syntax.
+fn fts_query(text: &str, separator: &str) -> String {
+    text.split_whitespace()
+        // A term of only punctuation would be an empty phrase, which FTS5 rejects in an OR.
+        .filter(|term| term.chars().any(char::is_alphanumeric))
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// The best matches for `query` across messages, summaries and digests, best first. Every word
+/// of the query has to match.
+pub async fn search(
+    pool: &SqlitePool,
+    query: &str,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<SearchHit>, Error> {
+    search_fts(pool, fts_query(query, " "), channel_id, guild_id, limit).await
+}
+
+/// Like [`search`], but anything matching any word of `text` is a hit, ranked by how well it
+/// matches. Suits questions, whose wording rarely all appears in the answer.
+pub async fn search_any(
+    pool: &SqlitePool,
+    text: &str,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<SearchHit>, Error> {
+    search_fts(pool, fts_query(text, " OR "), channel_id, guild_id, limit).await
+}
+
+async fn search_fts(
+    pool: &SqlitePool,
+    query: String,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<SearchHit>, Error> {
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+
+    sqlx::query_as(
+        "SELECT * FROM (
+            SELECT 'message' AS kind, m.id AS id, m.channel_id AS channel_id,
+                m.guild_id AS guild_id, m.timestamp AS timestamp,
+                snippet(messages_fts, -1, '**', '**', '…', 16) AS snippet,
+                bm25(messages_fts) AS rank, m.id AS message_id
+            FROM messages_fts
+            JOIN messages m ON m.id = messages_fts.rowid
+            WHERE messages_fts MATCH ?1 AND m.deleted_at IS NULL
+                AND (?2 IS NULL OR m.channel_id = ?2) AND (?3 IS NULL OR m.guild_id = ?3)
+            UNION ALL
+            SELECT 'summary', s.id, s.channel_id, s.guild_id, s.timestamp,
+                snippet(summaries_fts, 0, '**', '**', '…', 16), bm25(summaries_fts),
+                (SELECT MIN(id) FROM messages WHERE summary_id = s.id)
+            FROM summaries_fts
+            JOIN summaries s ON s.id = summaries_fts.rowid
+            WHERE summaries_fts MATCH ?1
+                AND (?2 IS NULL OR s.channel_id = ?2) AND (?3 IS NULL OR s.guild_id = ?3)
+            UNION ALL
+            SELECT 'digest', d.id, d.channel_id, d.guild_id, d.timestamp,
+                snippet(daily_digests_fts, 0, '**', '**', '…', 16), bm25(daily_digests_fts),
+                (SELECT MIN(m.id) FROM messages m
+                    JOIN summaries s ON s.id = m.summary_id
+                    WHERE s.daily_digest_id = d.id)
+            FROM daily_digests_fts
+            JOIN daily_digests d ON d.id = daily_digests_fts.rowid
+            WHERE daily_digests_fts MATCH ?1
+                AND (?2 IS NULL OR d.channel_id = ?2) AND (?3 IS NULL OR d.guild_id = ?3)
+        )
+        ORDER BY rank ASC
+        LIMIT ?4",
+    )
+    .bind(query)
+    .bind(channel_id)
+    .bind(guild_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// How much of the text a semantic search hit shows, as it has no matched words to centre on.
+const SEMANTIC_SNIPPET_CHARS: usize = 200;
+
+pub async fn fetch_unembedded_messages(
+    pool: &SqlitePool,
+    model: &str,
+    limit: i64,
+) -> Result<Vec<StoredMessage>, Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        "SELECT * FROM messages
+        WHERE deleted_at IS NULL AND NOT EXISTS (
+            SELECT 1 FROM message_embeddings e WHERE e.message_id = messages.id AND e.model = ?
+        )
+        ORDER BY id ASC
+        LIMIT ?",
+        model,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn fetch_unembedded_summaries(
+    pool: &SqlitePool,
+    model: &str,
+    limit: i64,
+) -> Result<Vec<Summary>, Error> {
+    sqlx::query_as!(
+        Summary,
+        r#"SELECT id AS "id!", daily_digest_id, text, timestamp, channel_id, guild_id, model,
+            prompt_tokens, completion_tokens, latency_ms, cost, includes_deleted_content
+        FROM summaries
+        WHERE NOT EXISTS (
+            SELECT 1 FROM summary_embeddings e WHERE e.summary_id = summaries.id AND e.model = ?
+        )
+        ORDER BY id ASC
+        LIMIT ?"#,
+        model,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_message_embeddings(
+    pool: &SqlitePool,
+    model: &str,
+    embeddings: &[(i64, Vec<f32>)],
+) -> Result<(), Error> {
+    let mut transaction = pool.begin().await?;
+    for (message_id, vector) in embeddings {
+        let vector = vector_to_blob(vector);
+        sqlx::query!(
+            "INSERT OR REPLACE INTO message_embeddings (message_id, model, vector) VALUES (?, ?, ?)",
+            message_id,
+            model,
+            vector
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+}
+
+pub async fn insert_summary_embeddings(
+    pool: &SqlitePool,
+    model: &str,
+    embeddings: &[(i64, Vec<f32>)],
+) -> Result<(), Error> {
+    let mut transaction = pool.begin().await?;
+    for (summary_id, vector) in embeddings {
+        let vector = vector_to_blob(vector);
+        sqlx::query!(
+            "INSERT OR REPLACE INTO summary_embeddings (summary_id, model, vector) VALUES (?, ?, ?)",
+            summary_id,
+            model,
+            vector
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+}
+
+#[derive(sqlx::FromRow)]
+struct EmbeddedRow {
+    kind: String,
+    id: i64,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    timestamp: NaiveDateTime,
+    text: String,
+    message_id: Option<i64>,
+    vector: Vec<u8>,
+}
+
+/// The `limit` messages and summaries embedded by `model` that are most similar to `vector`,
+/// best first. Compares against every stored vector in scope, which is plenty fast for the
+/// history of a few channels.
+pub async fn semantic_search(
+    pool: &SqlitePool,
+    model: &str,
+    vector: &[f32],
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<SearchHit>, Error> {
+    let rows: Vec<EmbeddedRow> = sqlx::query_as(
+        "SELECT 'message' AS kind, m.id AS id, m.channel_id AS channel_id,
+            m.guild_id AS guild_id, m.timestamp AS timestamp, m.content AS text,
+            m.id AS message_id, e.vector AS vector
+        FROM message_embeddings e
+        JOIN messages m ON m.id = e.message_id
+        WHERE e.model = ?1
+            AND (?2 IS NULL OR m.channel_id = ?2) AND (?3 IS NULL OR m.guild_id = ?3)
+        UNION ALL
+        SELECT 'summary', s.id, s.channel_id, s.guild_id, s.timestamp, s.text,
+            (SELECT MIN(id) FROM messages WHERE summary_id = s.id), e.vector
+        FROM summary_embeddings e
+        JOIN summaries s ON s.id = e.summary_id
+        WHERE e.model = ?1
+            AND (?2 IS NULL OR s.channel_id = ?2) AND (?3 IS NULL OR s.guild_id = ?3)",
+    )
+    .bind(model)
+    .bind(channel_id)
+    .bind(guild_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut scored: Vec<(f32, EmbeddedRow)> = rows
+        .into_iter()
+        .map(|row| (cosine_similarity(vector, &blob_to_vector(&row.vector)), row))
+        // Nothing in common at all isn't a match.
+        .filter(|(similarity, _)| *similarity > 0.0)
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    scored.truncate(limit.max(0) as usize);
+
+    Ok(scored
+        .into_iter()
+        .map(|(similarity, row)| {
+            let mut snippet: String = row.text.chars().take(SEMANTIC_SNIPPET_CHARS).collect();
+            if snippet.len() < row.text.len() {
+                snippet.push('…');
+            }
+            SearchHit {
+                kind: row.kind,
+                id: row.id,
+                channel_id: row.channel_id,
+                guild_id: row.guild_id,
+                timestamp: row.timestamp,
+                snippet,
+                rank: -similarity as f64,
+                message_id: row.message_id,
+            }
+        })
+        .collect())
+}
+
+fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn blob_to_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::gpt::{EmbeddingProvider, HashingEmbedder};
+
+    /// A migrated database that lives as long as the pool.
+    pub(crate) async fn memory_pool() -> SqlitePool {
+        // One connection, since every connection to `:memory:` opens a database of its own.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+        pool
+    }
+
+    pub(crate) fn message(id: i64, channel_id: i64, content: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            channel_id,
+            guild_id: Some(1),
+            author_id: 1,
+            display_name: "someone".to_string(),
+            content: content.to_string(),
+            timestamp: NaiveDateTime::default() + chrono::Duration::seconds(id),
+            reply_to: None,
+            edited_at: None,
+            summary_id: None,
+            job_id: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn fts_query_quotes_every_word() {
+        assert_eq!(fts_query("rust  release", " "), "\"rust\" \"release\"");
+        assert_eq!(fts_query("rust release", " OR "), "\"rust\" OR \"release\"");
+    }
+
+    #[test]
+    fn fts_query_neutralises_syntax() {
+        assert_eq!(
+            fts_query("say \"hi\" NEAR(x) -y", " "),
+            "\"say\" \"\"\"hi\"\"\" \"NEAR(x)\" \"-y\""
+        );
+        // Terms of only punctuation would be empty phrases.
+        assert_eq!(fts_query("what ? -- now", " OR "), "\"what\" OR \"now\"");
+        assert_eq!(fts_query("  ", " "), "");
+    }
+
+    #[test]
+    fn vectors_survive_blobs() {
+        let vector = vec![0.5, -1.25, 3.0e-8, f32::MAX];
+        assert_eq!(blob_to_vector(&vector_to_blob(&vector)), vector);
+        // A truncated blob loses the partial value rather than misreading it.
+        assert_eq!(blob_to_vector(&vector_to_blob(&vector)[..6]), vec![0.5]);
+    }
+
+    #[test]
+    fn cosine_similarity_ignores_length() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-3.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 5.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn semantic_search_ranks_by_similarity_within_a_model() {
+        let pool = memory_pool().await;
+        let texts = [
+            "the release is planned for friday",
+            "lunch at the new ramen place",
+            "friday release needs a changelog",
+            "release",
+        ];
+        for (id, text) in (1..).zip(texts) {
+            insert_message(&pool, &message(id, 10, text)).await.unwrap();
+        }
+
+        let embedder = HashingEmbedder::new(256);
+        let texts: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        let vectors = embedder.embed(&texts).await.unwrap().vectors;
+        let embeddings: Vec<(i64, Vec<f32>)> = (1..).zip(vectors).collect();
+        insert_message_embeddings(&pool, embedder.model(), &embeddings[..3])
+            .await
+            .unwrap();
+        // The best possible match, but from another model, so not comparable.
+        insert_message_embeddings(&pool, "other-model", &embeddings[3..])
+            .await
+            .unwrap();
+
+        let query = embedder
+            .embed(&["friday release".to_string()])
+            .await
+            .unwrap()
+            .vectors
+            .remove(0);
+        let hits = semantic_search(&pool, embedder.model(), &query, None, None, 2)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = hits.iter().map(|hit| hit.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+        assert!(hits[0].rank < hits[1].rank);
+
+        let hits = semantic_search(&pool, embedder.model(), &query, Some(11), None, 2)
+            .await
+            .unwrap();
+        assert!(hits.is_empty());
+    }
+
+    pub(crate) fn usage() -> LlmUsage {
+        LlmUsage {
+            model: "test".to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            latency_ms: 1,
+            cost: Some(0.01),
+        }
+    }
+
+    /// Stores a message and summarizes it on its own, returning the summary's id.
+    pub(crate) async fn summarized_message(pool: &SqlitePool, id: i64, channel_id: i64) -> i64 {
+        insert_message(pool, &message(id, channel_id, "hello"))
+            .await
+            .unwrap();
+        let job_id = insert_summarize_job(pool, channel_id, Some(1), &[id])
+            .await
+            .unwrap();
+        complete_summarize_job(pool, job_id, &format!("summary {id}"), &usage())
+            .await
+            .unwrap()
+    }
+
+    fn page_ids(summaries: &[Summary]) -> Vec<i64> {
+        summaries.iter().map(|s| s.id).collect()
+    }
+
+    #[tokio::test]
+    async fn list_filter_pages_and_filters() {
+        let pool = memory_pool().await;
+        for id in 1..=6 {
+            summarized_message(&pool, id, if id % 2 == 0 { 20 } else { 10 }).await;
+        }
+        // Spread the summaries a day apart, the first one on 2024-01-01.
+        sqlx::query(
+            "UPDATE summaries SET timestamp = datetime('2023-12-31', '+' || id || ' days')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let day = |d: u32| {
+            chrono::NaiveDate::from_ymd_opt(2024, 1, d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let all = || ListFilter {
+            channel_id: None,
+            since: None,
+            until: None,
+            cursor: None,
+            ascending: false,
+            limit: 10,
+        };
+
+        let page = fetch_summaries_page(&pool, &all()).await.unwrap();
+        assert_eq!(page_ids(&page), vec![6, 5, 4, 3, 2, 1]);
+
+        let filter = ListFilter {
+            cursor: Some(5),
+            limit: 2,
+            ..all()
+        };
+        let page = fetch_summaries_page(&pool, &filter).await.unwrap();
+        assert_eq!(page_ids(&page), vec![4, 3]);
+
+        let filter = ListFilter {
+            cursor: Some(2),
+            ascending: true,
+            ..all()
+        };
+        let page = fetch_summaries_page(&pool, &filter).await.unwrap();
+        assert_eq!(page_ids(&page), vec![3, 4, 5, 6]);
+
+        let filter = ListFilter {
+            channel_id: Some(20),
+            ..all()
+        };
+        let page = fetch_summaries_page(&pool, &filter).await.unwrap();
+        assert_eq!(page_ids(&page), vec![6, 4, 2]);
+
+        // `since` is inclusive and `until` exclusive.
+        let filter = ListFilter {
+            since: Some(day(2)),
+            until: Some(day(5)),
+            ..all()
+        };
+        let page = fetch_summaries_page(&pool, &filter).await.unwrap();
+        assert_eq!(page_ids(&page), vec![4, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn token_use_is_recorded_at_most_once_a_minute() {
+        let pool = memory_pool().await;
+        let id = insert_api_token(&pool, "reader", "hash", "read")
+            .await
+            .unwrap();
+        let token = fetch_api_token(&pool, "hash").await.unwrap().unwrap();
+        assert_eq!(token.id, id);
+        assert!(token.last_used_at.is_none());
+
+        assert!(touch_api_token(&pool, id).await.unwrap());
+        assert!(!touch_api_token(&pool, id).await.unwrap());
+        let token = fetch_api_token(&pool, "hash").await.unwrap().unwrap();
+        assert!(token.last_used_at.is_some());
+
+        sqlx::query("UPDATE api_tokens SET last_used_at = datetime('now', '-2 minutes')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(touch_api_token(&pool, id).await.unwrap());
+
+        revoke_api_token(&pool, id).await.unwrap();
+        assert!(fetch_api_token(&pool, "hash").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn edits_replace_content_of_stored_messages() {
+        let pool = memory_pool().await;
+        insert_message(&pool, &message(1, 10, "before"))
+            .await
+            .unwrap();
+        let edited_at = NaiveDateTime::default() + chrono::Duration::hours(1);
+
+        assert!(update_message_content(&pool, 1, "after", Some(edited_at))
+            .await
+            .unwrap());
+        let stored = fetch_message(&pool, 1).await.unwrap().unwrap();
+        assert_eq!(stored.content, "after");
+        assert_eq!(stored.edited_at, Some(edited_at));
+
+        // Messages that were never stored, or were deleted, are left alone.
+        assert!(!update_message_content(&pool, 2, "after", None)
+            .await
+            .unwrap());
+        delete_messages(&pool, &[1]).await.unwrap();
+        assert!(!update_message_content(&pool, 1, "again", None)
+            .await
+            .unwrap());
+        assert_eq!(fetch_message(&pool, 1).await.unwrap().unwrap().content, "");
+    }
+
+    #[tokio::test]
+    async fn deleting_unsummarized_messages_tombstones_them() {
+        let pool = memory_pool().await;
+        for id in 1..=3 {
+            insert_message(&pool, &message(id, 10, "secret"))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(delete_messages(&pool, &[1, 2, 99]).await.unwrap(), 2);
+        // Deleting again counts nothing new.
+        assert_eq!(delete_messages(&pool, &[1]).await.unwrap(), 0);
+
+        let deleted = fetch_message(&pool, 1).await.unwrap().unwrap();
+        assert_eq!(deleted.content, "");
+        assert!(deleted.deleted_at.is_some());
+        let unqueued: Vec<i64> = fetch_unqueued_messages(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(unqueued, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn deleting_summarized_messages_flags_their_summaries() {
+        let pool = memory_pool().await;
+        let flagged = summarized_message(&pool, 1, 10).await;
+        let untouched = summarized_message(&pool, 2, 10).await;
+
+        assert_eq!(delete_messages(&pool, &[1]).await.unwrap(), 1);
+        let deleted = fetch_message(&pool, 1).await.unwrap().unwrap();
+        assert_eq!(deleted.content, "");
+        assert_eq!(deleted.summary_id, Some(flagged));
+
+        let summary = fetch_summary(&pool, flagged).await.unwrap().unwrap();
+        assert!(summary.includes_deleted_content);
+        assert_eq!(summary.text, "summary 1");
+        let summary = fetch_summary(&pool, untouched).await.unwrap().unwrap();
+        assert!(!summary.includes_deleted_content);
+    }
+
+    #[tokio::test]
+    async fn interrupted_jobs_are_requeued() {
+        let pool = memory_pool().await;
+        insert_message(&pool, &message(1, 10, "hello"))
+            .await
+            .unwrap();
+        let job_id = insert_summarize_job(&pool, 10, Some(1), &[1])
+            .await
+            .unwrap();
+
+        let job = claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (job.id, job.status, job.attempts),
+            (job_id, JobStatus::InProgress, 1)
+        );
+        // A job in progress isn't claimed twice, until a restart requeues it.
+        assert!(claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(requeue_in_progress_jobs(&pool).await.unwrap(), 1);
+        assert_eq!(count_pending_summarize_jobs(&pool).await.unwrap(), 1);
+        let job = claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((job.id, job.attempts), (job_id, 2));
+        assert_eq!(requeue_in_progress_jobs(&pool).await.unwrap(), 1);
+        assert_eq!(requeue_in_progress_jobs(&pool).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_are_retried_up_to_their_attempt_limit() {
+        let pool = memory_pool().await;
+        insert_message(&pool, &message(1, 10, "hello"))
+            .await
+            .unwrap();
+        let job_id = insert_summarize_job(&pool, 10, Some(1), &[1])
+            .await
+            .unwrap();
+
+        for attempt in 1..=3 {
+            let job = claim_next_summarize_job(&pool, None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(job.attempts, attempt);
+            let status = fail_summarize_job(&pool, job_id, "boom", 3, 0)
+                .await
+                .unwrap();
+            let expected = if attempt < 3 {
+                JobStatus::Pending
+            } else {
+                JobStatus::Failed
+            };
+            assert_eq!(status, expected);
+        }
+        assert!(claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .is_none());
+        // Its messages stay queued rather than being picked up again on their own.
+        assert!(fetch_unqueued_messages(&pool).await.unwrap().is_empty());
+
+        // Until replay retries it.
+        assert_eq!(retry_summarize_jobs(&pool, Some(20)).await.unwrap(), 0);
+        assert_eq!(retry_summarize_jobs(&pool, Some(10)).await.unwrap(), 1);
+        let job = claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.attempts, 1);
+        assert_eq!(job.last_error.as_deref(), Some("boom"));
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_wait_before_their_retry() {
+        let pool = memory_pool().await;
+        insert_message(&pool, &message(1, 10, "hello"))
+            .await
+            .unwrap();
+        let job_id = insert_summarize_job(&pool, 10, Some(1), &[1])
+            .await
+            .unwrap();
+
+        claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let status = fail_summarize_job(&pool, job_id, "boom", 3, 3600)
+            .await
+            .unwrap();
+        assert_eq!(status, JobStatus::Pending);
+        assert!(claim_next_summarize_job(&pool, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn digests_come_with_their_summaries() {
+        let pool = memory_pool().await;
+        let mut summary_ids = vec![];
+        for (id, channel_id) in [(1, 10), (2, 10), (3, 20)] {
+            summary_ids.push(summarized_message(&pool, id, channel_id).await);
+        }
+        let first = insert_daily_digest(
+            &pool,
+            "digest of 10".to_string(),
+            Some(10),
+            Some(1),
+            summary_ids[..2].to_vec(),
+            &usage(),
+        )
+        .await
+        .unwrap();
+        let second = insert_daily_digest(
+            &pool,
+            "digest of 20".to_string(),
+            Some(20),
+            Some(1),
+            summary_ids[2..].to_vec(),
+            &usage(),
+        )
+        .await
+        .unwrap();
+
+        let digests = fetch_daily_digests(&pool).await.unwrap();
+        let ids: Vec<i64> = digests.iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![first, second]);
+        let texts: Vec<&str> = digests[0]
+            .summaries
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["summary 1", "summary 2"]);
+
+        let digest = fetch_daily_digest(&pool, second).await.unwrap().unwrap();
+        assert_eq!(digest.summaries.len(), 1);
+        assert!(fetch_daily_digest(&pool, 999).await.unwrap().is_none());
+
+        let latest = fetch_latest_daily_digest(&pool, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.id, second);
+        let latest = fetch_latest_daily_digest(&pool, Some(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.id, first);
+        assert_eq!(latest.summaries.len(), 2);
+        assert!(fetch_latest_daily_digest(&pool, Some(30))
+            .await
+            .unwrap()
+            .is_none());
+
+        // The limit applies to digests, not to the rows of their summaries.
+        let page = fetch_daily_digests_page(
+            &pool,
+            &ListFilter {
+                channel_id: None,
+                since: None,
+                until: None,
+                cursor: Some(second),
+                ascending: false,
+                limit: 1,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, first);
+        assert_eq!(page[0].summaries.len(), 2);
+    }
+}
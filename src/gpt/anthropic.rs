@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{Completion, LlmError, SummaryProvider, Usage};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Deserialize, Debug)]
+pub struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    #[serde(default)]
+    usage: MessagesUsage,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentBlock {
+    #[serde(default)]
+    text: String,
+}
+
+/// Talks to the Anthropic messages API.
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+    max_tokens: usize,
+}
+
+impl AnthropicProvider {
+    pub fn new(
+        base_url: &str,
+        api_key: String,
+        model: &str,
+        max_tokens: usize,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Could not build HTTP client"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+            max_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for AnthropicProvider {
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        let response = self
+            .client
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&json!({
+                "model": self.model,
+                "system": prompt,
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": text,
+                    }
+                ],
+                "max_tokens": self.max_tokens,
+            }))
+            .send()
+            .await?;
+        let result = LlmError::check(response)
+            .await?
+            .json::<MessagesResponse>()
+            .await?;
+
+        let content: Vec<String> = result.content.into_iter().map(|block| block.text).collect();
+        Ok(Completion {
+            text: content.join(""),
+            usage: Usage {
+                prompt_tokens: result.usage.input_tokens,
+                completion_tokens: result.usage.output_tokens,
+            },
+        })
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        let response = self
+            .client
+            .get(format!("{}/models", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .send()
+            .await?;
+        LlmError::check(response).await.map(|_| ())
+    }
+}
@@ -0,0 +1,45 @@
+use axum::async_trait;
+
+use super::{Completion, LlmError, SummaryProvider, Usage};
+
+const PREVIEW_CHARS: usize = 80;
+
+/// A deterministic, offline provider. It returns the configured fixture if there is one, and
+/// otherwise a short description of the input, so the pipeline can run without any LLM.
+pub struct EchoProvider {
+    fixture: Option<String>,
+}
+
+impl EchoProvider {
+    pub fn new(fixture: Option<String>) -> Self {
+        Self { fixture }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for EchoProvider {
+    async fn summarize(&self, _prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        if let Some(fixture) = &self.fixture {
+            return Ok(completion(fixture.clone()));
+        }
+
+        let first_line: String = text
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(PREVIEW_CHARS)
+            .collect();
+        Ok(completion(format!(
+            "Summary of {} lines: {first_line}",
+            text.lines().count()
+        )))
+    }
+}
+
+fn completion(text: String) -> Completion {
+    Completion {
+        text,
+        usage: Usage::default(),
+    }
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+
+/// Why a request to an LLM provider failed, split into failures worth retrying and fatal ones.
+#[derive(Debug)]
+pub enum LlmError {
+    /// The provider is rate limiting us (HTTP 429).
+    RateLimited { retry_after: Option<Duration> },
+    /// The provider failed on its side (HTTP 5xx).
+    Server {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// The request timed out or the connection failed.
+    Transport(reqwest::Error),
+    /// The provider rejected the request; sending it again will not help.
+    Rejected { status: StatusCode, body: String },
+    /// The provider answered with something we could not understand.
+    InvalidResponse(String),
+    /// The provider is misconfigured, e.g. a missing API key.
+    Config(String),
+}
+
+impl LlmError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LlmError::RateLimited { .. } | LlmError::Server { .. } | LlmError::Transport(_)
+        )
+    }
+
+    /// A short name for the kind of failure, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LlmError::RateLimited { .. } => "rate_limited",
+            LlmError::Server { .. } => "server",
+            LlmError::Transport(_) => "transport",
+            LlmError::Rejected { .. } => "rejected",
+            LlmError::InvalidResponse(_) => "invalid_response",
+            LlmError::Config(_) => "config",
+        }
+    }
+
+    /// How long the provider asked us to wait before trying again, if it said.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::RateLimited { retry_after } | LlmError::Server { retry_after, .. } => {
+                *retry_after
+            }
+            _ => None,
+        }
+    }
+
+    /// Passes successful responses through and classifies the rest.
+    pub async fn check(response: Response) -> Result<Response, LlmError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let retry_after = retry_after(response.headers());
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Err(LlmError::RateLimited { retry_after })
+        } else if status.is_server_error() {
+            Err(LlmError::Server {
+                status,
+                retry_after,
+            })
+        } else {
+            let body = response.text().await.unwrap_or_default();
+            Err(LlmError::Rejected { status, body })
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::RateLimited { retry_after } => match retry_after {
+                Some(wait) => write!(f, "rate limited, retry after {wait:?}"),
+                None => write!(f, "rate limited"),
+            },
+            LlmError::Server { status, .. } => write!(f, "provider error: {status}"),
+            LlmError::Transport(e) => write!(f, "transport error: {e}"),
+            LlmError::Rejected { status, body } => {
+                write!(f, "request rejected with {status}: {body}")
+            }
+            LlmError::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            LlmError::Config(reason) => write!(f, "provider misconfigured: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            LlmError::InvalidResponse(e.to_string())
+        } else if e.is_builder() {
+            // The request could not even be built, e.g. because `base_url` is not a valid URL;
+            // it will fail the same way every time.
+            LlmError::Config(e.to_string())
+        } else {
+            LlmError::Transport(e)
+        }
+    }
+}
+
+/// Reads the wait time out of `Retry-After`, `retry-after-ms` and the OpenAI and Anthropic
+/// rate limit reset headers, taking the longest one given.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let candidates = [
+        header("retry-after-ms")
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .map(Duration::from_millis),
+        header("retry-after").and_then(parse_retry_after),
+        header("x-ratelimit-reset-requests").and_then(parse_openai_duration),
+        header("x-ratelimit-reset-tokens").and_then(parse_openai_duration),
+        header("anthropic-ratelimit-requests-reset").and_then(parse_timestamp),
+        header("anthropic-ratelimit-tokens-reset").and_then(parse_timestamp),
+    ];
+    candidates.into_iter().flatten().max()
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .and_then(|date| (date.with_timezone(&Utc) - Utc::now()).to_std().ok())
+}
+
+fn parse_timestamp(value: &str) -> Option<Duration> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .and_then(|date| (date.with_timezone(&Utc) - Utc::now()).to_std().ok())
+}
+
+/// OpenAI reports resets as Go-style durations, e.g. `20ms`, `1s` or `6m0s`.
+fn parse_openai_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+        let seconds = match c {
+            'h' => amount * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                amount / 1000.0
+            }
+            'm' => amount * 60.0,
+            's' => amount,
+            _ => return None,
+        };
+        total += Duration::try_from_secs_f64(seconds).ok()?;
+    }
+
+    if number.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn retry_after_takes_seconds() {
+        assert_eq!(parse_retry_after("20"), Some(Duration::from_secs(20)));
+        assert_eq!(
+            parse_retry_after(" 1.5 "),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_takes_http_dates() {
+        let date = (Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let wait = parse_retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+
+        // Dates in the past mean there is nothing to wait for.
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn openai_durations() {
+        assert_eq!(
+            parse_openai_duration("1m30.5s"),
+            Some(Duration::from_millis(90_500))
+        );
+        assert_eq!(
+            parse_openai_duration("20ms"),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(parse_openai_duration("6s"), Some(Duration::from_secs(6)));
+        assert_eq!(
+            parse_openai_duration("1h2m"),
+            Some(Duration::from_secs(3720))
+        );
+        assert_eq!(parse_openai_duration("12"), None);
+        assert_eq!(parse_openai_duration("3d"), None);
+        assert_eq!(parse_openai_duration("s"), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_urls_are_config_errors() {
+        for url in ["not a url", "ftp://example.com/v1/chat"] {
+            let error: LlmError = reqwest::Client::new()
+                .post(url)
+                .send()
+                .await
+                .unwrap_err()
+                .into();
+            assert!(matches!(error, LlmError::Config(_)), "{url}: {error}");
+            assert!(!error.is_retryable());
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_the_longest_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        headers.insert("retry-after-ms", HeaderValue::from_static("500"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("1m0s"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(60)));
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,62 @@
+use axum::async_trait;
+
+use super::{EmbeddingProvider, Embeddings, LlmError, Usage};
+
+/// A deterministic, offline embedder. Each lowercased word is hashed to one dimension, so texts
+/// are similar when they share words, not meaning. Good enough for tests and small servers
+/// without an embeddings API.
+pub struct HashingEmbedder {
+    dimensions: usize,
+    model: String,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        let dimensions = dimensions.max(1);
+        Self {
+            dimensions,
+            model: format!("hashing-{dimensions}"),
+        }
+    }
+
+    fn embed_one(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let hash = fnv1a(&word.to_lowercase());
+            // The sign bit spreads collisions out instead of always adding them up.
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign;
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for HashingEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Embeddings, LlmError> {
+        Ok(Embeddings {
+            vectors: texts.iter().map(|text| self.embed_one(text)).collect(),
+            usage: Usage::default(),
+        })
+    }
+}
+
+/// FNV-1a, which unlike the standard library's hasher is stable across Rust versions, so stored
+/// vectors stay comparable.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
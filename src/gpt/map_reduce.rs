@@ -0,0 +1,230 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use sqlx::SqlitePool;
+use tracing::{info, warn};
+
+use super::{provider_from_config, Completion, LlmError, SummaryProvider, Tokenizer, Usage};
+use crate::config::{AppConfig, ModelPrice};
+use crate::db::LlmUsage;
+
+/// A summary, with what the LLM billed for all the requests it took.
+pub struct Summarized {
+    pub text: String,
+    pub usage: LlmUsage,
+}
+
+/// Summarizes inputs of any size. The input is split into chunks that fit in a single request,
+/// the chunks are summarized concurrently, and the resulting summaries are summarized again
+/// until a single summary is left.
+pub struct MapReduceSummarizer {
+    provider: Arc<dyn SummaryProvider>,
+    tokenizer: Tokenizer,
+    max_request_tokens: usize,
+    concurrency: usize,
+    model: String,
+    price: Option<ModelPrice>,
+}
+
+impl MapReduceSummarizer {
+    pub fn new(
+        provider: Arc<dyn SummaryProvider>,
+        tokenizer: Tokenizer,
+        max_request_tokens: usize,
+        concurrency: usize,
+        model: &str,
+        price: Option<ModelPrice>,
+    ) -> Self {
+        Self {
+            provider,
+            tokenizer,
+            max_request_tokens,
+            concurrency: concurrency.max(1),
+            model: model.to_string(),
+            price,
+        }
+    }
+
+    /// Records the usage of every request in `usage_log`, if given.
+    pub fn from_config(
+        config: &AppConfig,
+        usage_log: Option<Arc<SqlitePool>>,
+    ) -> Result<Self, LlmError> {
+        Ok(Self::with_provider(
+            provider_from_config(config, usage_log)?,
+            config,
+        ))
+    }
+
+    /// Like [`MapReduceSummarizer::from_config`], but sharing a provider built elsewhere.
+    pub fn with_provider(provider: Arc<dyn SummaryProvider>, config: &AppConfig) -> Self {
+        Self::new(
+            provider,
+            Tokenizer::from_config(config),
+            config.service.max_gpt_request_tokens,
+            config.summary.max_concurrent_requests,
+            &config.summary.model,
+            config.summary.prices.get(&config.summary.model).copied(),
+        )
+    }
+
+    /// Summarizes `items` (messages, log lines or summaries) as one body of text, keeping them in
+    /// order and only splitting between items unless a single item is too large on its own.
+    pub async fn summarize(&self, prompt: &str, items: &[String]) -> Result<Summarized, LlmError> {
+        let started = Instant::now();
+        let mut usage = Usage::default();
+        let budget = self
+            .max_request_tokens
+            .saturating_sub(self.tokenizer.count(prompt))
+            .max(1);
+
+        let mut chunks = self.chunk(items, budget);
+        let mut round = 1;
+        while chunks.len() > 1 {
+            info!(
+                "Summarizing {} chunks in round {round} of map-reduce",
+                chunks.len()
+            );
+            let chunk_count = chunks.len();
+            let completions: Vec<Completion> = stream::iter(chunks)
+                .map(|chunk| {
+                    let provider = self.provider.clone();
+                    let prompt = prompt.to_string();
+                    async move { provider.summarize(&prompt, &chunk).await }
+                })
+                .buffered(self.concurrency)
+                .try_collect()
+                .await?;
+            let summaries: Vec<String> = completions
+                .into_iter()
+                .map(|completion| {
+                    usage += completion.usage;
+                    completion.text
+                })
+                .collect();
+
+            let next = self.chunk(&summaries, budget);
+            if next.len() >= chunk_count {
+                // The summaries are as large as their inputs, so another round would not
+                // converge. Send what we have and let the provider truncate it.
+                warn!("Map-reduce summaries are not shrinking, summarizing them as-is");
+                let completion = self
+                    .provider
+                    .summarize(prompt, &summaries.join("\n"))
+                    .await?;
+                return Ok(self.summarized(completion, usage, started));
+            }
+            chunks = next;
+            round += 1;
+        }
+
+        let text = chunks.pop().unwrap_or_default();
+        let completion = self.provider.summarize(prompt, &text).await?;
+        Ok(self.summarized(completion, usage, started))
+    }
+
+    /// The final completion, billed for every request it took, which started at `started`.
+    fn summarized(&self, completion: Completion, mut usage: Usage, started: Instant) -> Summarized {
+        usage += completion.usage;
+        Summarized {
+            text: completion.text,
+            usage: LlmUsage {
+                model: self.model.clone(),
+                prompt_tokens: usage.prompt_tokens as i64,
+                completion_tokens: usage.completion_tokens as i64,
+                latency_ms: started.elapsed().as_millis() as i64,
+                cost: self.price.map(|price| usage.cost(&price)),
+            },
+        }
+    }
+
+    /// Greedily packs items into newline-joined chunks of at most `budget` tokens.
+    fn chunk(&self, items: &[String], budget: usize) -> Vec<String> {
+        let mut chunks = vec![];
+        let mut current = String::new();
+        let mut current_tokens = 0;
+
+        for item in items {
+            for piece in self.tokenizer.split(item, budget) {
+                let tokens = self.tokenizer.count(&piece);
+                // Count the newline joining it to the previous item too.
+                if !current.is_empty() && current_tokens + 1 + tokens > budget {
+                    chunks.push(std::mem::take(&mut current));
+                    current_tokens = 0;
+                }
+                if !current.is_empty() {
+                    current.push('\n');
+                    current_tokens += 1;
+                }
+                current.push_str(&piece);
+                current_tokens += tokens;
+            }
+        }
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::EchoProvider;
+
+    fn summarizer(max_request_tokens: usize) -> MapReduceSummarizer {
+        MapReduceSummarizer::new(
+            Arc::new(EchoProvider::new(Some("done".to_string()))),
+            Tokenizer::new("gpt-4", None),
+            max_request_tokens,
+            2,
+            "gpt-4",
+            None,
+        )
+    }
+
+    #[test]
+    fn chunks_fit_the_budget_with_their_separators() {
+        let summarizer = summarizer(100);
+        let items: Vec<String> = (0..40)
+            .map(|i| format!("message number {i} says hello there"))
+            .collect();
+        for budget in [1, 5, 9, 10, 11, 30] {
+            let chunks = summarizer.chunk(&items, budget);
+            assert!(chunks.len() > 1, "budget = {budget}");
+            for chunk in &chunks {
+                assert!(
+                    summarizer.tokenizer.count(chunk) <= budget,
+                    "budget = {budget}, chunk = {chunk:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_keep_items_in_order() {
+        let summarizer = summarizer(100);
+        let items: Vec<String> = (0..20).map(|i| format!("item {i}")).collect();
+        let chunks = summarizer.chunk(&items, 12);
+        assert_eq!(chunks.join("\n"), items.join("\n"));
+    }
+
+    #[test]
+    fn oversized_items_are_split() {
+        let summarizer = summarizer(100);
+        let item = "word ".repeat(100);
+        let chunks = summarizer.chunk(std::slice::from_ref(&item), 10);
+        assert!(chunks.len() >= 10);
+        assert_eq!(chunks.concat(), item);
+    }
+
+    #[tokio::test]
+    async fn summarizes_large_inputs_in_rounds() {
+        let summarizer = summarizer(60);
+        let items: Vec<String> = (0..200).map(|i| format!("message {i}")).collect();
+        let summarized = summarizer.summarize("Summarize:", &items).await.unwrap();
+        assert_eq!(summarized.text, "done");
+        assert_eq!(summarized.usage.model, "gpt-4");
+    }
+}
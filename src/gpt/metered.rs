@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use sqlx::SqlitePool;
+use tracing::error;
+
+use super::{Completion, EmbeddingProvider, Embeddings, LlmError, SummaryProvider, Usage};
+use crate::config::ModelPrice;
+use crate::db::{self, LlmUsage};
+use crate::metrics::metrics;
+
+/// Wraps a provider and records the latency, failures, token usage and estimated cost of every
+/// request in the metrics, and the usage of every request in the database, if it has one.
+pub struct MeteredProvider {
+    inner: Arc<dyn SummaryProvider>,
+    model: String,
+    price: Option<ModelPrice>,
+    usage_log: Option<Arc<SqlitePool>>,
+}
+
+impl MeteredProvider {
+    pub fn new(
+        inner: Arc<dyn SummaryProvider>,
+        model: &str,
+        price: Option<ModelPrice>,
+        usage_log: Option<Arc<SqlitePool>>,
+    ) -> Self {
+        Self {
+            inner,
+            model: model.to_string(),
+            price,
+            usage_log,
+        }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for MeteredProvider {
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        let metrics = metrics();
+        let started = Instant::now();
+        let result = self.inner.summarize(prompt, text).await;
+        let latency = started.elapsed();
+        metrics
+            .llm_request_duration
+            .with_label_values(&[&self.model])
+            .observe(latency.as_secs_f64());
+
+        match &result {
+            Ok(completion) => {
+                record_usage(
+                    &self.model,
+                    self.price.as_ref(),
+                    self.usage_log.as_deref(),
+                    completion.usage,
+                    latency,
+                )
+                .await
+            }
+            Err(e) => metrics
+                .llm_failures
+                .with_label_values(&[&self.model, e.kind()])
+                .inc(),
+        }
+        result
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        self.inner.check().await
+    }
+}
+
+/// [`MeteredProvider`] for embedding providers.
+pub struct MeteredEmbedder {
+    inner: Arc<dyn EmbeddingProvider>,
+    price: Option<ModelPrice>,
+    usage_log: Option<Arc<SqlitePool>>,
+}
+
+impl MeteredEmbedder {
+    pub fn new(
+        inner: Arc<dyn EmbeddingProvider>,
+        price: Option<ModelPrice>,
+        usage_log: Option<Arc<SqlitePool>>,
+    ) -> Self {
+        Self {
+            inner,
+            price,
+            usage_log,
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for MeteredEmbedder {
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Embeddings, LlmError> {
+        let model = self.inner.model();
+        let started = Instant::now();
+        let result = self.inner.embed(texts).await;
+        let latency = started.elapsed();
+        metrics()
+            .llm_request_duration
+            .with_label_values(&[model])
+            .observe(latency.as_secs_f64());
+
+        match &result {
+            Ok(embeddings) => {
+                record_usage(
+                    model,
+                    self.price.as_ref(),
+                    self.usage_log.as_deref(),
+                    embeddings.usage,
+                    latency,
+                )
+                .await
+            }
+            Err(e) => metrics()
+                .llm_failures
+                .with_label_values(&[model, e.kind()])
+                .inc(),
+        }
+        result
+    }
+}
+
+/// Counts the tokens and estimated cost of a successful request in the metrics, and records its
+/// usage in `usage_log`.
+async fn record_usage(
+    model: &str,
+    price: Option<&ModelPrice>,
+    usage_log: Option<&SqlitePool>,
+    usage: Usage,
+    latency: Duration,
+) {
+    let metrics = metrics();
+    metrics
+        .llm_tokens
+        .with_label_values(&[model, "prompt"])
+        .inc_by(usage.prompt_tokens);
+    metrics
+        .llm_tokens
+        .with_label_values(&[model, "completion"])
+        .inc_by(usage.completion_tokens);
+    if let Some(price) = price {
+        metrics
+            .llm_cost_dollars
+            .with_label_values(&[model])
+            .inc_by(usage.cost(price));
+    }
+
+    // Recorded here rather than with what the request was for, since it is billed even if what
+    // it was for fails later on.
+    if let Some(db) = usage_log {
+        let usage = LlmUsage {
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens as i64,
+            completion_tokens: usage.completion_tokens as i64,
+            latency_ms: latency.as_millis() as i64,
+            cost: price.map(|price| usage.cost(price)),
+        };
+        if let Err(e) = db::insert_llm_usage(db, &usage).await {
+            error!("Could not record LLM usage: {e}");
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::config::{AppConfig, EmbeddingProviderKind, ModelPrice, ProviderKind};
+
+mod anthropic;
+mod echo;
+mod error;
+mod hashing;
+mod map_reduce;
+mod metered;
+mod openai;
+mod retry;
+mod tokens;
+mod truncate;
+
+pub use anthropic::AnthropicProvider;
+pub use echo::EchoProvider;
+pub use error::LlmError;
+pub use hashing::HashingEmbedder;
+pub use map_reduce::MapReduceSummarizer;
+pub use metered::{MeteredEmbedder, MeteredProvider};
+pub use openai::{OpenAiEmbedder, OpenAiProvider};
+pub use retry::{RetryingEmbedder, RetryingProvider};
+pub use tokens::Tokenizer;
+pub use truncate::TruncatingProvider;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const OLLAMA_BASE_URL: &str = "http://localhost:11434/v1";
+
+/// What a provider generated for a request.
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub text: String,
+    pub usage: Usage,
+}
+
+/// The vectors a provider returned for a request, one for each text, in order.
+#[derive(Clone, Debug)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Usage,
+}
+
+/// Tokens a provider billed for a request, as it reported them. Zero for providers that don't
+/// report usage.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+impl Usage {
+    /// The estimated cost in dollars.
+    pub fn cost(&self, price: &ModelPrice) -> f64 {
+        (self.prompt_tokens as f64 * price.prompt
+            + self.completion_tokens as f64 * price.completion)
+            / 1_000_000.0
+    }
+}
+
+/// A backend capable of turning a chunk of text into a summary.
+#[async_trait]
+pub trait SummaryProvider: Send + Sync {
+    /// Summarizes `text`, using `prompt` as the system instructions.
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError>;
+
+    /// Checks that the provider is reachable and accepts our credentials, without generating
+    /// anything.
+    async fn check(&self) -> Result<(), LlmError> {
+        Ok(())
+    }
+}
+
+/// A backend capable of turning texts into vectors, whose cosine similarity says how alike the
+/// texts are.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Names the vector space. Vectors are only comparable with others from the same model.
+    fn model(&self) -> &str;
+
+    /// Embeds each of `texts`, in order.
+    async fn embed(&self, texts: &[String]) -> Result<Embeddings, LlmError>;
+}
+
+/// Builds the provider selected by the `[summary]` config section, metered, wrapped in the
+/// configured retry policy and truncated to the request token budget. The usage of every request
+/// is recorded in `usage_log`, if given.
+pub fn provider_from_config(
+    config: &AppConfig,
+    usage_log: Option<Arc<SqlitePool>>,
+) -> Result<Arc<dyn SummaryProvider>, LlmError> {
+    let tokenizer = Tokenizer::from_config(config);
+    let max_request_tokens = config.service.max_gpt_request_tokens;
+    let config = &config.summary;
+    let timeout = Duration::from_secs(config.retry.request_timeout_seconds);
+    let base_url = |default: &'static str| config.base_url.as_deref().unwrap_or(default);
+    let provider: Arc<dyn SummaryProvider> = match config.provider {
+        ProviderKind::OpenAi => {
+            let api_key = std::env::var("OPEN_AI_SECRET")
+                .map_err(|_| LlmError::Config("No OPEN_AI_SECRET provided".to_string()))?;
+            Arc::new(OpenAiProvider::new(
+                base_url(OPENAI_BASE_URL),
+                Some(api_key),
+                &config.model,
+                config.max_tokens,
+                timeout,
+            ))
+        }
+        ProviderKind::Anthropic => {
+            let api_key = std::env::var("ANTHROPIC_API_KEY")
+                .map_err(|_| LlmError::Config("No ANTHROPIC_API_KEY provided".to_string()))?;
+            Arc::new(AnthropicProvider::new(
+                base_url(ANTHROPIC_BASE_URL),
+                api_key,
+                &config.model,
+                config.max_tokens,
+                timeout,
+            ))
+        }
+        // Ollama and llama.cpp both expose an OpenAI-compatible chat completions API.
+        ProviderKind::Ollama => Arc::new(OpenAiProvider::new(
+            base_url(OLLAMA_BASE_URL),
+            None,
+            &config.model,
+            config.max_tokens,
+            timeout,
+        )),
+        ProviderKind::Echo => Arc::new(EchoProvider::new(config.fixture.clone())),
+    };
+    // Metered inside the retries, so every attempt is measured.
+    let provider = Arc::new(MeteredProvider::new(
+        provider,
+        &config.model,
+        config.prices.get(&config.model).copied(),
+        usage_log,
+    ));
+    let provider = Arc::new(RetryingProvider::new(provider, config.retry.clone()));
+    Ok(Arc::new(TruncatingProvider::new(
+        provider,
+        tokenizer,
+        max_request_tokens,
+    )))
+}
+
+/// Builds the embedding provider selected by the `[embeddings]` config section, metered and
+/// wrapped in its retry policy, or `None` if the section is missing. The usage of every request
+/// is recorded in `usage_log`, if given, priced from `[summary.prices]` like summaries are.
+pub fn embedding_provider_from_config(
+    config: &AppConfig,
+    usage_log: Option<Arc<SqlitePool>>,
+) -> Result<Option<Arc<dyn EmbeddingProvider>>, LlmError> {
+    let prices = &config.summary.prices;
+    let Some(config) = &config.embeddings else {
+        return Ok(None);
+    };
+    let timeout = Duration::from_secs(config.retry.request_timeout_seconds);
+    let base_url = |default: &'static str| config.base_url.as_deref().unwrap_or(default);
+    let provider: Arc<dyn EmbeddingProvider> = match config.provider {
+        EmbeddingProviderKind::OpenAi => {
+            let api_key = std::env::var("OPEN_AI_SECRET")
+                .map_err(|_| LlmError::Config("No OPEN_AI_SECRET provided".to_string()))?;
+            Arc::new(OpenAiEmbedder::new(
+                base_url(OPENAI_BASE_URL),
+                Some(api_key),
+                &config.model,
+                timeout,
+            ))
+        }
+        EmbeddingProviderKind::Ollama => Arc::new(OpenAiEmbedder::new(
+            base_url(OLLAMA_BASE_URL),
+            None,
+            &config.model,
+            timeout,
+        )),
+        EmbeddingProviderKind::Hashing => Arc::new(HashingEmbedder::new(config.dimensions)),
+    };
+    let provider = Arc::new(MeteredEmbedder::new(
+        provider,
+        prices.get(&config.model).copied(),
+        usage_log,
+    ));
+    Ok(Some(Arc::new(RetryingEmbedder::new(
+        provider,
+        config.retry.clone(),
+    ))))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use daily_discord_summarizer::mock_llm;
+
+    use super::*;
+
+    /// Serves the bundled mock LLM on an ephemeral port, answering with each of `responses` in
+    /// turn after rate limiting the first `rate_limit_first` requests, and returns a config that
+    /// summarizes with it through the OpenAI provider.
+    pub(crate) async fn mock_llm_config(responses: &[&str], rate_limit_first: usize) -> AppConfig {
+        let app = mock_llm::router(
+            responses.iter().map(|r| r.to_string()).collect(),
+            rate_limit_first,
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // The mock accepts any key, but the provider won't start without one.
+        std::env::set_var("OPEN_AI_SECRET", "mock");
+        serde_json::from_value(serde_json::json!({
+            "database": { "url": "sqlite::memory:" },
+            "service": {
+                "produce_digest_interval_seconds": 3600,
+                "port": 3000,
+                "host": "127.0.0.1",
+                "max_gpt_request_tokens": 2048,
+            },
+            "discord": { "channel_ids": ["10", "20"] },
+            "summary": {
+                "provider": "openai",
+                "base_url": format!("http://{address}/v1"),
+                "model": "gpt-4",
+                "prompt": "Summarize:",
+                "max_tokens": 100,
+                "retry": { "initial_backoff_ms": 1, "max_backoff_ms": 10 },
+                "prices": { "gpt-4": { "prompt": 1.0, "completion": 2.0 } },
+            },
+        }))
+        .unwrap()
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{Completion, EmbeddingProvider, Embeddings, LlmError, SummaryProvider, Usage};
+
+#[derive(Deserialize, Debug)]
+pub struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Usage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Choice {
+    message: GptMessage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GptMessage {
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    #[serde(default)]
+    usage: Usage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Talks to the OpenAI chat completions API, or anything that speaks it (Ollama, llama.cpp).
+pub struct OpenAiProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    max_tokens: usize,
+}
+
+impl OpenAiProvider {
+    pub fn new(
+        base_url: &str,
+        api_key: Option<String>,
+        model: &str,
+        max_tokens: usize,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Could not build HTTP client"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+            max_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for OpenAiProvider {
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        let mut request = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&json!({
+                "model": self.model,
+                "messages": [
+                    {
+                        "role": "system",
+                        "content": prompt,
+                    },
+                    {
+                        "role": "user",
+                        "content": text,
+                    }
+                ],
+                "max_tokens": self.max_tokens,
+            }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+        let result = LlmError::check(response)
+            .await?
+            .json::<ChatCompletionResponse>()
+            .await?;
+
+        let usage = result.usage;
+        result
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| Completion {
+                text: choice.message.content,
+                usage,
+            })
+            .ok_or_else(|| {
+                LlmError::InvalidResponse(
+                    "Chat completion response contained no choices".to_string(),
+                )
+            })
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        let mut request = self.client.get(format!("{}/models", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        LlmError::check(request.send().await?).await.map(|_| ())
+    }
+}
+
+/// Talks to the OpenAI embeddings API, or anything that speaks it (Ollama).
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(base_url: &str, api_key: Option<String>, model: &str, timeout: Duration) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Could not build HTTP client"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Embeddings, LlmError> {
+        let mut request = self
+            .client
+            .post(format!("{}/embeddings", self.base_url))
+            .json(&json!({
+                "model": self.model,
+                "input": texts,
+            }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+        let mut result = LlmError::check(response)
+            .await?
+            .json::<EmbeddingResponse>()
+            .await?;
+
+        if result.data.len() != texts.len() {
+            return Err(LlmError::InvalidResponse(format!(
+                "Asked for {} embeddings but got {}",
+                texts.len(),
+                result.data.len()
+            )));
+        }
+        result.data.sort_by_key(|data| data.index);
+        Ok(Embeddings {
+            vectors: result.data.into_iter().map(|data| data.embedding).collect(),
+            usage: result.usage,
+        })
+    }
+}
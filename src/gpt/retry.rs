@@ -0,0 +1,232 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::async_trait;
+use tracing::warn;
+
+use super::{Completion, EmbeddingProvider, Embeddings, LlmError, SummaryProvider};
+use crate::config::RetryConfig;
+
+/// Wraps a provider and retries retryable failures with exponential backoff, waiting at least as
+/// long as the provider asked when it sends rate limit headers.
+pub struct RetryingProvider {
+    inner: Arc<dyn SummaryProvider>,
+    policy: RetryConfig,
+}
+
+impl RetryingProvider {
+    pub fn new(inner: Arc<dyn SummaryProvider>, policy: RetryConfig) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for RetryingProvider {
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        with_retries(&self.policy, "Summary", || {
+            self.inner.summarize(prompt, text)
+        })
+        .await
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        // A health check wants to know how things are now, not after retries.
+        self.inner.check().await
+    }
+}
+
+/// [`RetryingProvider`] for embedding providers.
+pub struct RetryingEmbedder {
+    inner: Arc<dyn EmbeddingProvider>,
+    policy: RetryConfig,
+}
+
+impl RetryingEmbedder {
+    pub fn new(inner: Arc<dyn EmbeddingProvider>, policy: RetryConfig) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for RetryingEmbedder {
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Embeddings, LlmError> {
+        with_retries(&self.policy, "Embedding", || self.inner.embed(texts)).await
+    }
+}
+
+async fn with_retries<T, F, Fut>(
+    policy: &RetryConfig,
+    what: &str,
+    mut request: F,
+) -> Result<T, LlmError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, LlmError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Ok(response) => return Ok(response),
+            Err(e) if e.is_retryable() && attempt < policy.max_attempts => {
+                let max = Duration::from_millis(policy.max_backoff_ms);
+                let wait = e
+                    .retry_after()
+                    .map(|wait| wait.min(max))
+                    .unwrap_or_default()
+                    .max(backoff(policy, attempt));
+                warn!(
+                    "{what} request failed on attempt {attempt}/{}: {e}, retrying in {wait:?}",
+                    policy.max_attempts
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn backoff(policy: &RetryConfig, attempt: u32) -> Duration {
+    let factor = policy.multiplier.powi(attempt.saturating_sub(1) as i32);
+    let millis = (policy.initial_backoff_ms as f64 * factor).min(policy.max_backoff_ms as f64);
+    Duration::from_millis(millis as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Instant;
+
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::gpt::Usage;
+
+    /// Fails its first `failures` requests with the error `error` makes, then succeeds.
+    struct FlakyProvider {
+        failures: u32,
+        error: fn() -> LlmError,
+        calls: AtomicU32,
+    }
+
+    impl FlakyProvider {
+        fn new(failures: u32, error: fn() -> LlmError) -> Arc<Self> {
+            Arc::new(Self {
+                failures,
+                error,
+                calls: AtomicU32::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl SummaryProvider for FlakyProvider {
+        async fn summarize(&self, _prompt: &str, _text: &str) -> Result<Completion, LlmError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err((self.error)());
+            }
+            Ok(Completion {
+                text: "summary".to_string(),
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 20,
+            multiplier: 2.0,
+            request_timeout_seconds: 1,
+        }
+    }
+
+    fn server_error() -> LlmError {
+        LlmError::Server {
+            status: StatusCode::BAD_GATEWAY,
+            retry_after: None,
+        }
+    }
+
+    async fn summarize(provider: &Arc<FlakyProvider>, policy: RetryConfig) -> Result<(), LlmError> {
+        RetryingProvider::new(provider.clone(), policy)
+            .summarize("prompt", "text")
+            .await
+            .map(drop)
+    }
+
+    #[tokio::test]
+    async fn retries_until_it_succeeds() {
+        let provider = FlakyProvider::new(2, server_error);
+        summarize(&provider, policy(3)).await.unwrap();
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let provider = FlakyProvider::new(10, server_error);
+        let error = summarize(&provider, policy(3)).await.unwrap_err();
+        assert!(matches!(error, LlmError::Server { .. }));
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_are_not_retried() {
+        let rejected = || LlmError::Rejected {
+            status: StatusCode::BAD_REQUEST,
+            body: String::new(),
+        };
+        let config = || LlmError::Config("no key".to_string());
+        for error in [rejected as fn() -> LlmError, config] {
+            let provider = FlakyProvider::new(1, error);
+            assert!(summarize(&provider, policy(5)).await.is_err());
+            assert_eq!(provider.calls.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_the_provider_asks() {
+        let rate_limited = || LlmError::RateLimited {
+            retry_after: Some(Duration::from_millis(300)),
+        };
+        let provider = FlakyProvider::new(1, rate_limited);
+        let started = Instant::now();
+        summarize(
+            &provider,
+            RetryConfig {
+                max_backoff_ms: 1000,
+                ..policy(2)
+            },
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // But no longer than the longest backoff.
+        let provider = FlakyProvider::new(1, || LlmError::RateLimited {
+            retry_after: Some(Duration::from_secs(3600)),
+        });
+        let started = Instant::now();
+        summarize(&provider, policy(2)).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = RetryConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            ..policy(10)
+        };
+        let waits: Vec<u64> = (1..=6)
+            .map(|attempt| backoff(&policy, attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(waits, vec![100, 200, 400, 800, 1000, 1000]);
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Encoding};
+use tiktoken_rs::CoreBPE;
+
+use crate::config::AppConfig;
+
+/// Counts and truncates text in the tokens a model actually sees.
+#[derive(Clone)]
+pub struct Tokenizer {
+    bpe: Arc<Mutex<CoreBPE>>,
+}
+
+impl Tokenizer {
+    /// Picks the encoding by name (e.g. `cl100k_base`) if one is given, and otherwise by model.
+    /// Models tiktoken does not know about, like Claude or Llama, fall back to `cl100k_base`,
+    /// which is close enough for budgeting.
+    pub fn new(model: &str, encoding: Option<&str>) -> Self {
+        let encoding = encoding
+            .and_then(encoding_from_name)
+            .or_else(|| get_tokenizer(model))
+            .unwrap_or(Encoding::Cl100kBase);
+
+        let bpe = match encoding {
+            Encoding::O200kBase => tiktoken_rs::o200k_base_singleton(),
+            Encoding::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Encoding::P50kBase => tiktoken_rs::p50k_base_singleton(),
+            Encoding::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+            Encoding::R50kBase | Encoding::Gpt2 => tiktoken_rs::r50k_base_singleton(),
+        };
+        Self { bpe }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self::new(&config.summary.model, config.summary.tokenizer.as_deref())
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.lock().encode_ordinary(text).len()
+    }
+
+    /// Cuts `text` down to at most `max_tokens` tokens, returning it untouched if it already fits.
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let bpe = self.bpe.lock();
+        let tokens = bpe.encode_ordinary(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+
+        // A multi-byte character can straddle the cut, in which case decoding fails; drop tokens
+        // until it lands on a character boundary.
+        let mut end = max_tokens;
+        while end > 0 {
+            if let Ok(truncated) = bpe.decode(tokens[..end].to_vec()) {
+                return truncated;
+            }
+            end -= 1;
+        }
+        String::new()
+    }
+
+    /// Splits `text` into consecutive pieces of at most `max_tokens` tokens each.
+    pub fn split(&self, text: &str, max_tokens: usize) -> Vec<String> {
+        let bpe = self.bpe.lock();
+        let tokens = bpe.encode_ordinary(text);
+        let max_tokens = max_tokens.max(1);
+
+        let mut pieces = vec![];
+        let mut start = 0;
+        while start < tokens.len() {
+            let limit = (start + max_tokens).min(tokens.len());
+            // A multi-byte character can straddle the cut, in which case decoding fails; move the
+            // cut back until it lands on a character boundary. If not even one character fits,
+            // move it forward instead so we always make progress.
+            let (end, piece) = (start + 1..=limit)
+                .rev()
+                .chain(limit + 1..=tokens.len())
+                .find_map(|end| Some((end, bpe.decode(tokens[start..end].to_vec()).ok()?)))
+                .unwrap_or_else(|| (tokens.len(), String::new()));
+            pieces.push(piece);
+            start = end;
+        }
+        pieces
+    }
+}
+
+fn encoding_from_name(name: &str) -> Option<Encoding> {
+    match name {
+        "o200k_base" => Some(Encoding::O200kBase),
+        "cl100k_base" => Some(Encoding::Cl100kBase),
+        "p50k_base" => Some(Encoding::P50kBase),
+        "p50k_edit" => Some(Encoding::P50kEdit),
+        "r50k_base" | "gpt2" => Some(Encoding::R50kBase),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_pieces_fit_and_join_back() {
+        let tokenizer = Tokenizer::new("gpt-4", None);
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(50);
+        let pieces = tokenizer.split(&text, 7);
+        assert!(pieces.len() > 1);
+        assert!(pieces.iter().all(|piece| tokenizer.count(piece) <= 7));
+        assert_eq!(pieces.concat(), text);
+    }
+
+    #[test]
+    fn split_keeps_multibyte_characters_whole() {
+        let tokenizer = Tokenizer::new("gpt-4", None);
+        let text = "日本語のテキスト🦀🦀🦀 and ünïcödé";
+        for max_tokens in 1..5 {
+            let pieces = tokenizer.split(text, max_tokens);
+            assert_eq!(pieces.concat(), text, "max_tokens = {max_tokens}");
+        }
+    }
+
+    #[test]
+    fn split_of_empty_text_is_empty() {
+        let tokenizer = Tokenizer::new("gpt-4", None);
+        assert!(tokenizer.split("", 10).is_empty());
+    }
+}
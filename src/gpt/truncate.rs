@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use tracing::warn;
+
+use super::{Completion, LlmError, SummaryProvider, Tokenizer};
+
+/// Wraps a provider and trims the input so the prompt and text together fit in the request
+/// token budget.
+pub struct TruncatingProvider {
+    inner: Arc<dyn SummaryProvider>,
+    tokenizer: Tokenizer,
+    max_request_tokens: usize,
+}
+
+impl TruncatingProvider {
+    pub fn new(
+        inner: Arc<dyn SummaryProvider>,
+        tokenizer: Tokenizer,
+        max_request_tokens: usize,
+    ) -> Self {
+        Self {
+            inner,
+            tokenizer,
+            max_request_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl SummaryProvider for TruncatingProvider {
+    async fn summarize(&self, prompt: &str, text: &str) -> Result<Completion, LlmError> {
+        let budget = self
+            .max_request_tokens
+            .saturating_sub(self.tokenizer.count(prompt));
+        let text_tokens = self.tokenizer.count(text);
+        if text_tokens <= budget {
+            return self.inner.summarize(prompt, text).await;
+        }
+
+        warn!("Summary input is {text_tokens} tokens, truncating it to {budget}");
+        let truncated = self.tokenizer.truncate(text, budget);
+        self.inner.summarize(prompt, &truncated).await
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        self.inner.check().await
+    }
+}
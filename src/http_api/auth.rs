@@ -0,0 +1,392 @@
+use std::sync::Arc;
+
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tracing::{debug, error};
+
+use crate::config::{ApiConfig, Scope};
+use crate::db;
+
+/// Checks bearer tokens against those in the config and those stored in the database.
+#[derive(Clone)]
+pub struct Authenticator {
+    db: Arc<SqlitePool>,
+    config: Arc<ApiConfig>,
+}
+
+impl Authenticator {
+    pub fn new(db: Arc<SqlitePool>, config: ApiConfig) -> Self {
+        Self {
+            db,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Middleware state for [`require_scope`], demanding `scope` of every request.
+    pub fn require(&self, scope: Scope) -> RequiredScope {
+        RequiredScope {
+            authenticator: self.clone(),
+            scope,
+            token_source: TokenSource::Header,
+        }
+    }
+
+    /// Like [`Authenticator::require`], but also accepts the token as a `token` query parameter,
+    /// for clients like feed readers that can't send headers.
+    pub fn require_in_url(&self, scope: Scope) -> RequiredScope {
+        RequiredScope {
+            token_source: TokenSource::Query,
+            ..self.require(scope)
+        }
+    }
+
+    /// Like [`Authenticator::require`], but also accepts the token from the [`TOKEN_COOKIE`]
+    /// cookie, for browsers. Requests without a valid token are redirected to `login_path`.
+    pub fn require_in_cookie(&self, scope: Scope, login_path: &'static str) -> RequiredScope {
+        RequiredScope {
+            token_source: TokenSource::Cookie { login_path },
+            ..self.require(scope)
+        }
+    }
+
+    /// Whether a request with `token`, or one without a token for `None`, may use `scope`.
+    pub async fn check(&self, token: Option<&str>, scope: Scope) -> Result<Access, sqlx::Error> {
+        Ok(match self.scopes(token).await? {
+            Some(scopes) if scopes.iter().any(|s| s.grants(scope)) => Access::Granted,
+            Some(_) if token.is_some() => Access::Forbidden,
+            _ => Access::Unauthorized,
+        })
+    }
+
+    /// The scopes granted to a request with `token`, or to one without a token for `None`.
+    /// Returns `None` for tokens that are unknown or revoked.
+    async fn scopes(&self, token: Option<&str>) -> Result<Option<Vec<Scope>>, sqlx::Error> {
+        let Some(token) = token else {
+            return Ok(Some(self.config.anonymous_scopes.clone()));
+        };
+
+        let token_hash = hash_token(token);
+        if let Some(configured) = self
+            .config
+            .tokens
+            .iter()
+            .find(|t| t.sha256.eq_ignore_ascii_case(&token_hash))
+        {
+            debug!("Authenticated API request with token {}", configured.name);
+            return Ok(Some(configured.scopes.clone()));
+        }
+
+        let Some(stored) = db::fetch_api_token(&self.db, &token_hash).await? else {
+            return Ok(None);
+        };
+        debug!(
+            "Authenticated API request with token {} ({})",
+            stored.id, stored.name
+        );
+        self.record_use(&stored);
+        Ok(Some(parse_scopes(&stored.scopes)))
+    }
+
+    /// Records that a stored token was used, at most once every [`LAST_USED_RESOLUTION`] so that
+    /// requests don't all queue up behind the database's writer. The request doesn't wait on it.
+    fn record_use(&self, token: &db::ApiToken) {
+        let recently_used = token.last_used_at.is_some_and(|last_used_at| {
+            Utc::now().naive_utc() - last_used_at < LAST_USED_RESOLUTION
+        });
+        if recently_used {
+            return;
+        }
+
+        let db = self.db.clone();
+        let id = token.id;
+        tokio::spawn(async move {
+            if let Err(e) = db::touch_api_token(&db, id).await {
+                error!("Could not record use of API token {id}: {e}");
+            }
+        });
+    }
+}
+
+/// How precisely `last_used_at` of stored tokens is kept.
+const LAST_USED_RESOLUTION: Duration = Duration::minutes(1);
+
+/// The name of the cookie the HTML UI keeps the API token in.
+pub const TOKEN_COOKIE: &str = "api_token";
+
+pub enum Access {
+    Granted,
+    /// The token is valid but lacks the scope.
+    Forbidden,
+    /// There is no token, or it is unknown or revoked.
+    Unauthorized,
+}
+
+/// Where a token is accepted from, besides the `Authorization` header.
+#[derive(Clone, Copy)]
+enum TokenSource {
+    Header,
+    Query,
+    Cookie { login_path: &'static str },
+}
+
+#[derive(Clone)]
+pub struct RequiredScope {
+    authenticator: Authenticator,
+    scope: Scope,
+    token_source: TokenSource,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Rejects requests without a token granting the required scope: 401 if the token is missing or
+/// invalid, 403 if it is valid but lacks the scope.
+pub async fn require_scope(
+    State(required): State<RequiredScope>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let other_token = match required.token_source {
+        TokenSource::Header => None,
+        TokenSource::Query => Query::<TokenQuery>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(query)| query.token),
+        TokenSource::Cookie { .. } => cookie(&request, TOKEN_COOKIE),
+    };
+    let token = header_token.or(other_token.as_deref());
+
+    match required.authenticator.check(token, required.scope).await {
+        Ok(Access::Granted) => next.run(request).await,
+        Ok(Access::Forbidden) => StatusCode::FORBIDDEN.into_response(),
+        Ok(Access::Unauthorized) => match required.token_source {
+            TokenSource::Cookie { login_path } => Redirect::to(login_path).into_response(),
+            _ => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+            )
+                .into_response(),
+        },
+        Err(e) => {
+            error!("Could not look up API token: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn cookie(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Generates a new random API token.
+pub fn generate_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// The hex-encoded SHA-256 of a token, which is all that is stored of it.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Scopes as stored in the database, comma-separated.
+pub fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split(',')
+        .filter_map(|s| Scope::from_name(s.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use reqwest::redirect::Policy;
+
+    use super::*;
+    use crate::config::ApiTokenConfig;
+    use crate::db::tests::memory_pool;
+
+    /// Serves a route per way of passing a token, each behind `scope`, and returns its base URL.
+    async fn serve(authenticator: &Authenticator, scope: Scope) -> String {
+        let layer =
+            |required: RequiredScope| middleware::from_fn_with_state(required, require_scope);
+        let app = Router::new()
+            .route("/header", get(|| async { "ok" }))
+            .route_layer(layer(authenticator.require(scope)))
+            .merge(
+                Router::new()
+                    .route("/query", get(|| async { "ok" }))
+                    .route_layer(layer(authenticator.require_in_url(scope))),
+            )
+            .merge(
+                Router::new()
+                    .route("/cookie", get(|| async { "ok" }))
+                    .route_layer(layer(authenticator.require_in_cookie(scope, "/login"))),
+            );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{address}")
+    }
+
+    /// An authenticator with a `read` and an `admin` token stored in the database, a revoked
+    /// `read` token, and a `trigger` token in the config.
+    async fn authenticator(anonymous_scopes: Vec<Scope>) -> Authenticator {
+        let db = memory_pool().await;
+        db::insert_api_token(&db, "reader", &hash_token("read-token"), "read")
+            .await
+            .unwrap();
+        db::insert_api_token(&db, "admin", &hash_token("admin-token"), "admin")
+            .await
+            .unwrap();
+        let revoked = db::insert_api_token(&db, "old", &hash_token("revoked-token"), "read")
+            .await
+            .unwrap();
+        db::revoke_api_token(&db, revoked).await.unwrap();
+
+        let config = ApiConfig {
+            anonymous_scopes,
+            tokens: vec![ApiTokenConfig {
+                name: "cron".to_string(),
+                sha256: hash_token("config-token").to_uppercase(),
+                scopes: vec![Scope::Trigger],
+            }],
+        };
+        Authenticator::new(Arc::new(db), config)
+    }
+
+    async fn status(url: &str, token: Option<&str>) -> u16 {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await.unwrap().status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn invalid_tokens_are_unauthorized() {
+        let base = serve(&authenticator(vec![]).await, Scope::Read).await;
+        let url = format!("{base}/header");
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        assert_eq!(status(&url, Some("unknown-token")).await, 401);
+        assert_eq!(status(&url, Some("revoked-token")).await, 401);
+    }
+
+    #[tokio::test]
+    async fn tokens_need_the_scope() {
+        let authenticator = authenticator(vec![]).await;
+        let read = serve(&authenticator, Scope::Read).await;
+        let trigger = serve(&authenticator, Scope::Trigger).await;
+        let admin = serve(&authenticator, Scope::Admin).await;
+
+        assert_eq!(
+            status(&format!("{read}/header"), Some("read-token")).await,
+            200
+        );
+        assert_eq!(
+            status(&format!("{trigger}/header"), Some("read-token")).await,
+            403
+        );
+        assert_eq!(
+            status(&format!("{admin}/header"), Some("read-token")).await,
+            403
+        );
+
+        // Tokens from the config are checked by hash too.
+        assert_eq!(
+            status(&format!("{trigger}/header"), Some("config-token")).await,
+            200
+        );
+        assert_eq!(
+            status(&format!("{read}/header"), Some("config-token")).await,
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_grants_every_scope() {
+        let authenticator = authenticator(vec![]).await;
+        for scope in [Scope::Read, Scope::Trigger, Scope::Admin] {
+            let base = serve(&authenticator, scope).await;
+            assert_eq!(
+                status(&format!("{base}/header"), Some("admin-token")).await,
+                200
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_scopes_are_granted_without_a_token() {
+        let authenticator = authenticator(vec![Scope::Read]).await;
+        let read = serve(&authenticator, Scope::Read).await;
+        let trigger = serve(&authenticator, Scope::Trigger).await;
+
+        assert_eq!(status(&format!("{read}/header"), None).await, 200);
+        assert_eq!(status(&format!("{trigger}/header"), None).await, 401);
+        // A bad token is still rejected rather than treated as no token.
+        assert_eq!(
+            status(&format!("{read}/header"), Some("unknown-token")).await,
+            401
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_are_taken_from_the_query_or_cookie_where_allowed() {
+        let base = serve(&authenticator(vec![]).await, Scope::Read).await;
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap();
+        let get = |path: &str, cookie: Option<&str>| {
+            let mut request = client.get(format!("{base}{path}"));
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", cookie);
+            }
+            async move { request.send().await.unwrap() }
+        };
+
+        assert_eq!(get("/query?token=read-token", None).await.status(), 200);
+        assert_eq!(get("/query?token=revoked-token", None).await.status(), 401);
+        assert_eq!(get("/header?token=read-token", None).await.status(), 401);
+
+        let cookie = "theme=dark; api_token=read-token";
+        assert_eq!(get("/cookie", Some(cookie)).await.status(), 200);
+        assert_eq!(get("/header", Some(cookie)).await.status(), 401);
+        let response = get("/cookie", Some("api_token=unknown-token")).await;
+        assert!(response.status().is_redirection());
+        assert_eq!(response.headers()["location"], "/login");
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Extension;
+use chrono::{NaiveDateTime, SecondsFormat, Utc};
+use sqlx::SqlitePool;
+use tracing::error;
+
+use super::digest_title;
+use super::markdown::{escape, render_markdown};
+use crate::db;
+
+/// How many of the most recent digests a feed includes.
+const FEED_ENTRIES: i64 = 50;
+const FEED_AUTHOR: &str = "Daily Discord Summarizer";
+
+pub async fn digests_feed_handler(
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    digests_feed(&db, None).await
+}
+
+pub async fn channel_digests_feed_handler(
+    Path(channel_id): Path<i64>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    digests_feed(&db, Some(channel_id)).await
+}
+
+async fn digests_feed(
+    db: &SqlitePool,
+    channel_id: Option<i64>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let filter = db::ListFilter {
+        channel_id,
+        since: None,
+        until: None,
+        cursor: None,
+        ascending: false,
+        limit: FEED_ENTRIES,
+    };
+    let digests = db::fetch_daily_digests_page(db, &filter)
+        .await
+        .map_err(|e| {
+            error!("Could not fetch digests for feed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        atom_feed(channel_id, &digests),
+    ))
+}
+
+/// Writes digests, newest first, as an Atom feed. Entry ids are derived from digest ids, so they
+/// stay the same however often the feed is regenerated.
+fn atom_feed(channel_id: Option<i64>, digests: &[db::DailyDigest]) -> String {
+    let (feed_id, title) = match channel_id {
+        Some(channel_id) => (
+            format!("urn:daily-discord-summarizer:feed:channel:{channel_id}"),
+            format!("Daily digests of channel {channel_id}"),
+        ),
+        None => (
+            "urn:daily-discord-summarizer:feed:digests".to_string(),
+            "Daily digests".to_string(),
+        ),
+    };
+    let updated = digests
+        .first()
+        .map(|d| atom_date(d.timestamp))
+        .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    let mut feed = String::new();
+    feed.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    _ = writeln!(feed, "  <id>{feed_id}</id>");
+    _ = writeln!(feed, "  <title>{}</title>", escape(&title));
+    _ = writeln!(feed, "  <updated>{updated}</updated>");
+    _ = writeln!(feed, "  <author><name>{FEED_AUTHOR}</name></author>");
+
+    for digest in digests {
+        let title = digest_title(digest.rollup, digest.channel_id);
+        let date = atom_date(digest.timestamp);
+
+        feed.push_str("  <entry>\n");
+        _ = writeln!(
+            feed,
+            "    <id>urn:daily-discord-summarizer:digest:{}</id>",
+            digest.id
+        );
+        _ = writeln!(
+            feed,
+            "    <title>{} ({})</title>",
+            escape(&title),
+            digest.timestamp.format("%Y-%m-%d %H:%M UTC")
+        );
+        _ = writeln!(feed, "    <published>{date}</published>");
+        _ = writeln!(feed, "    <updated>{date}</updated>");
+        _ = writeln!(
+            feed,
+            "    <content type=\"html\">{}</content>",
+            escape(&render_markdown(&digest.text))
+        );
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+fn atom_date(timestamp: NaiveDateTime) -> String {
+    timestamp
+        .and_utc()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
@@ -0,0 +1,94 @@
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// URL schemes links and images may point to. Anything else, like `javascript:` or `data:`,
+/// could run script in the page.
+const ALLOWED_SCHEMES: &[&str] = &["http:", "https:", "mailto:"];
+
+/// Renders digest markdown to HTML. Since digests are written by an LLM from untrusted chat
+/// messages, raw HTML is escaped rather than passed through, and links and images that don't
+/// point to an http, https or mailto URL are reduced to their text.
+pub fn render_markdown(markdown: &str) -> String {
+    let parser = Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    )
+    .filter_map(|event| match event {
+        Event::Html(raw) => Some(Event::Text(raw)),
+        Event::Start(Tag::Link(_, ref url, _) | Tag::Image(_, ref url, _))
+        | Event::End(Tag::Link(_, ref url, _) | Tag::Image(_, ref url, _))
+            if !is_allowed_url(url) =>
+        {
+            None
+        }
+        event => Some(event),
+    });
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, parser);
+    rendered
+}
+
+fn is_allowed_url(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    ALLOWED_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+}
+
+/// Escapes text for use in HTML or XML content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn javascript_links_become_text() {
+        let rendered = render_markdown("[click](javascript:alert(document.cookie)) me");
+        assert!(!rendered.contains("<a"), "{rendered}");
+        assert!(!rendered.contains("javascript:"), "{rendered}");
+        assert!(rendered.contains("click me"), "{rendered}");
+
+        let rendered = render_markdown("<JavaScript:alert(1)>");
+        assert!(!rendered.contains("<a"), "{rendered}");
+    }
+
+    #[test]
+    fn data_images_become_text() {
+        let rendered = render_markdown("![cat](data:text/html;base64,PHNjcmlwdD4=)");
+        assert!(!rendered.contains("<img"), "{rendered}");
+        assert!(!rendered.contains("data:"), "{rendered}");
+        assert!(rendered.contains("cat"), "{rendered}");
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let rendered = render_markdown("hi <script>alert(1)</script>\n\n<script>alert(2)</script>");
+        assert!(!rendered.contains("<script>"), "{rendered}");
+        assert!(rendered.contains("&lt;script&gt;"), "{rendered}");
+    }
+
+    #[test]
+    fn web_and_mail_links_are_kept() {
+        let rendered = render_markdown("[docs](https://example.com/a) [mail](mailto:a@b.c)");
+        assert!(
+            rendered.contains("<a href=\"https://example.com/a\">docs</a>"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("<a href=\"mailto:a@b.c\">mail</a>"),
+            "{rendered}"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use crate::db;
+use crate::gpt::EmbeddingProvider;
+use crate::metrics::metrics;
+use crate::services::digests::DigestTrigger;
+use crate::services::embeddings::semantic_search;
+use crate::services::health::{Health, HealthReport};
+use crate::services::supervisor::{ServiceStatus, Supervisor};
+
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::error;
+
+pub mod auth;
+pub mod feeds;
+mod markdown;
+pub mod ui;
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 200;
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query parameters shared by the list endpoints.
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// Page size, at most `MAX_PAGE_LIMIT`.
+    limit: Option<i64>,
+    /// The `next_cursor` of the previous page.
+    cursor: Option<i64>,
+    /// Only items created at or after this time.
+    since: Option<DateTime<Utc>>,
+    /// Only items created before this time.
+    until: Option<DateTime<Utc>>,
+    channel_id: Option<i64>,
+    /// Newest first (`desc`, the default) or oldest first (`asc`).
+    #[serde(default)]
+    order: Order,
+}
+
+impl ListParams {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    fn filter(&self) -> db::ListFilter {
+        db::ListFilter {
+            channel_id: self.channel_id,
+            since: self.since.map(|since| since.naive_utc()),
+            until: self.until.map(|until| until.naive_utc()),
+            cursor: self.cursor,
+            ascending: matches!(self.order, Order::Asc),
+            // One more than requested, to tell whether there is a next page.
+            limit: self.limit() + 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    items: Vec<T>,
+    /// Pass as `cursor` to get the next page; `null` on the last page.
+    next_cursor: Option<i64>,
+}
+
+impl<T> Page<T> {
+    fn new(mut items: Vec<T>, limit: i64, id: impl Fn(&T) -> i64) -> Self {
+        let mut next_cursor = None;
+        if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            next_cursor = items.last().map(id);
+        }
+        Self { items, next_cursor }
+    }
+}
+
+pub async fn summaries_handler(
+    Query(params): Query<ListParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Json<Page<db::Summary>>, StatusCode> {
+    let summaries = db::fetch_summaries_page(&db, &params.filter())
+        .await
+        .map_err(|e| {
+            error!("Could not fetch summaries: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(Page::new(summaries, params.limit(), |s| s.id)))
+}
+
+pub async fn daily_digests_handler(
+    Query(params): Query<ListParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Json<Page<db::DailyDigest>>, StatusCode> {
+    let digests = db::fetch_daily_digests_page(&db, &params.filter())
+        .await
+        .map_err(|e| {
+            error!("Could not fetch daily digests: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(Page::new(digests, params.limit(), |d| d.id)))
+}
+
+#[derive(Deserialize)]
+pub struct LatestParams {
+    /// Only consider digests of this channel.
+    channel_id: Option<i64>,
+}
+
+pub async fn daily_digest_handler(
+    Path(id): Path<i64>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Json<db::DailyDigest>, StatusCode> {
+    found(db::fetch_daily_digest(&db, id).await, "daily digest")
+}
+
+pub async fn latest_daily_digest_handler(
+    Query(params): Query<LatestParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Json<db::DailyDigest>, StatusCode> {
+    found(
+        db::fetch_latest_daily_digest(&db, params.channel_id).await,
+        "latest daily digest",
+    )
+}
+
+pub async fn summary_handler(
+    Path(id): Path<i64>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Json<db::Summary>, StatusCode> {
+    found(db::fetch_summary(&db, id).await, "summary")
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Full-text search for every word of the query.
+    #[default]
+    Text,
+    /// Nearest embeddings to the query's, when embeddings are configured.
+    Semantic,
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    #[serde(default)]
+    mode: SearchMode,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    /// At most `MAX_SEARCH_LIMIT`.
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    hit: db::SearchHit,
+    /// Jumps to the Discord message the result came from.
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    results: Vec<SearchResult>,
+}
+
+pub async fn search_handler(
+    Query(params): Query<SearchParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+    Extension(embedder): Extension<Option<Arc<dyn EmbeddingProvider>>>,
+) -> Result<Json<SearchResults>, StatusCode> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let (channel_id, guild_id) = (params.channel_id, params.guild_id);
+    let hits = match params.mode {
+        SearchMode::Text => db::search(&db, &params.q, channel_id, guild_id, limit)
+            .await
+            .map_err(eyre::Report::from),
+        SearchMode::Semantic => {
+            let embedder = embedder.ok_or(StatusCode::NOT_IMPLEMENTED)?;
+            semantic_search(&db, &*embedder, &params.q, channel_id, guild_id, limit).await
+        }
+    }
+    .map_err(|e| {
+        error!("Could not search: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let results = hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            url: hit.jump_url(),
+            hit,
+        })
+        .collect();
+    Ok(Json(SearchResults { results }))
+}
+
+/// Starts producing digests now, without waiting for the schedule.
+pub async fn run_digests_handler(Extension(trigger): Extension<DigestTrigger>) -> StatusCode {
+    if trigger.trigger() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// The state of each background service, by name.
+pub async fn services_handler(
+    Extension(supervisor): Extension<Supervisor>,
+) -> Json<BTreeMap<&'static str, ServiceStatus>> {
+    Json(supervisor.statuses())
+}
+
+/// Metrics in the Prometheus text format.
+pub async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+/// Liveness: 200 while the database answers and no service has failed for good, 503 otherwise.
+/// Anyone may ask, so it tells no more than that.
+pub async fn healthz_handler(
+    Extension(health): Extension<Health>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let healthy = health.healthy().await;
+    (status_for(healthy), Json(json!({ "healthy": healthy })))
+}
+
+/// Readiness: 200 once the bot is also connected to Discord and can reach the LLM, 503 otherwise.
+/// Anyone may ask, so it tells no more than that.
+pub async fn readyz_handler(
+    Extension(health): Extension<Health>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let ready = health.report().await.ready;
+    (status_for(ready), Json(json!({ "ready": ready })))
+}
+
+/// Everything the health checks are based on, including the errors services failed with.
+pub async fn health_handler(Extension(health): Extension<Health>) -> Json<HealthReport> {
+    Json(health.report().await)
+}
+
+fn status_for(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Responds with the item, 404 if there is none, or 500 if fetching it failed.
+fn found<T>(result: Result<Option<T>, sqlx::Error>, what: &str) -> Result<Json<T>, StatusCode> {
+    match result {
+        Ok(Some(item)) => Ok(Json(item)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Could not fetch {what}: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// A human-readable title for a digest.
+fn digest_title(rollup: bool, channel_id: Option<i64>) -> String {
+    match (rollup, channel_id) {
+        (true, _) => "Roll-up digest".to_string(),
+        (false, Some(channel_id)) => format!("Digest of channel {channel_id}"),
+        (false, None) => "Digest".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(query: &str) -> ListParams {
+        let uri = format!("/summaries?{query}").parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn page_sets_a_cursor_when_there_are_more_items() {
+        let page = Page::new(vec![9, 8, 7], 2, |&id| id);
+        assert_eq!(page.items, vec![9, 8]);
+        assert_eq!(page.next_cursor, Some(8));
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let page = Page::new(vec![2, 1], 2, |&id| id);
+        assert_eq!(page.items, vec![2, 1]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = Page::new(Vec::<i64>::new(), 2, |&id| id);
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn list_params_clamp_the_limit_and_fetch_one_extra() {
+        assert_eq!(params("").limit(), DEFAULT_PAGE_LIMIT);
+        assert_eq!(params("limit=0").limit(), 1);
+        assert_eq!(params("limit=100000").limit(), MAX_PAGE_LIMIT);
+        assert_eq!(params("limit=5").filter().limit, 6);
+    }
+
+    #[test]
+    fn list_params_become_a_filter() {
+        let filter = params("cursor=3&channel_id=7&order=asc&since=2024-01-02T03:04:05Z").filter();
+        assert_eq!(filter.cursor, Some(3));
+        assert_eq!(filter.channel_id, Some(7));
+        assert!(filter.ascending);
+        assert_eq!(filter.since.unwrap().to_string(), "2024-01-02 03:04:05");
+        assert!(filter.until.is_none());
+        assert!(!params("").filter().ascending);
+    }
+}
@@ -0,0 +1,403 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::{Extension, Form};
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tracing::error;
+
+use super::auth::{Access, Authenticator, TOKEN_COOKIE};
+use super::markdown::{escape, render_markdown};
+use super::{digest_title, Page};
+use crate::config::Scope;
+use crate::db;
+
+pub const LOGIN_PATH: &str = "/ui/login";
+const PAGE_SIZE: i64 = 20;
+const SEARCH_RESULTS: i64 = 50;
+const PREVIEW_CHARS: usize = 240;
+const TOKEN_COOKIE_MAX_AGE_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// Pages only need their inline styles, links, and images from the web. Anything else that slips
+/// into a digest can't load, run script, or post forms elsewhere.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; \
+    img-src http: https:; form-action 'self'; base-uri 'none'; frame-ancestors 'none'";
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; max-width: 50rem; margin: 0 auto; padding: 1rem; line-height: 1.5; }
+header { display: flex; gap: 1rem; align-items: center; border-bottom: 1px solid #ccc; padding-bottom: 0.5rem; }
+header form { margin: 0; }
+header .search { flex: 1; }
+header input[type=search] { width: 100%; }
+.meta { color: #666; font-size: 0.9em; }
+.error { color: #b00; }
+li { margin-bottom: 0.75rem; }
+";
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    cursor: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    token: String,
+}
+
+/// The most recent digests, newest first, a page at a time.
+pub async fn digests_page(
+    Query(params): Query<ListParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Html<String>, StatusCode> {
+    let filter = db::ListFilter {
+        channel_id: None,
+        since: None,
+        until: None,
+        cursor: params.cursor,
+        ascending: false,
+        limit: PAGE_SIZE + 1,
+    };
+    let digests = db::fetch_daily_digests_page(&db, &filter)
+        .await
+        .map_err(|e| internal_error("digests", e))?;
+    let page = Page::new(digests, PAGE_SIZE, |d| d.id);
+
+    let mut body = String::new();
+    if page.items.is_empty() {
+        body.push_str("<p>No digests yet.</p>");
+    }
+    body.push_str("<ul>");
+    for digest in &page.items {
+        digest_item(
+            &mut body,
+            digest.id,
+            digest.rollup,
+            digest.channel_id,
+            digest.timestamp,
+            &digest.text,
+        );
+    }
+    body.push_str("</ul><nav>");
+    if params.cursor.is_some() {
+        body.push_str("<a href=\"/ui\">Newest</a> ");
+    }
+    if let Some(cursor) = page.next_cursor {
+        _ = write!(body, "<a href=\"/ui?cursor={cursor}\">Older</a>");
+    }
+    body.push_str("</nav>");
+
+    Ok(layout("Digests", "", &body))
+}
+
+pub async fn digest_page(
+    Path(id): Path<i64>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Html<String>, StatusCode> {
+    let digest = db::fetch_daily_digest(&db, id)
+        .await
+        .map_err(|e| internal_error("digest", e))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut body = String::new();
+    _ = write!(
+        body,
+        "<p class=\"meta\">{}</p>{}",
+        format_timestamp(digest.timestamp),
+        render_markdown(&digest.text)
+    );
+    if !digest.summaries.is_empty() {
+        body.push_str("<h2>Summaries</h2><ul>");
+        for summary in &digest.summaries {
+            summary_item(&mut body, summary);
+        }
+        body.push_str("</ul>");
+    }
+
+    let title = digest_title(digest.rollup, digest.channel_id);
+    Ok(layout(&title, "", &body))
+}
+
+pub async fn summary_page(
+    Path(id): Path<i64>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Html<String>, StatusCode> {
+    let summary = db::fetch_summary(&db, id)
+        .await
+        .map_err(|e| internal_error("summary", e))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut body = String::new();
+    _ = write!(
+        body,
+        "<p class=\"meta\">{}",
+        format_timestamp(summary.timestamp)
+    );
+    if let Some(channel_id) = summary.channel_id {
+        _ = write!(body, " in channel {channel_id}");
+    }
+    if let Some(digest_id) = summary.daily_digest_id {
+        _ = write!(
+            body,
+            ", part of <a href=\"/ui/digests/{digest_id}\">digest {digest_id}</a>"
+        );
+    }
+    _ = write!(body, "</p>{}", render_markdown(&summary.text));
+
+    Ok(layout("Summary", "", &body))
+}
+
+pub async fn search_page(
+    Query(params): Query<SearchParams>,
+    Extension(db): Extension<Arc<SqlitePool>>,
+) -> Result<Html<String>, StatusCode> {
+    let query = params.q.trim();
+    if query.is_empty() {
+        return Ok(layout(
+            "Search",
+            "",
+            "<p>Search messages, summaries and digests.</p>",
+        ));
+    }
+
+    let hits = db::search(&db, query, None, None, SEARCH_RESULTS)
+        .await
+        .map_err(|e| internal_error("search results", e))?;
+
+    let mut body = String::new();
+    if hits.is_empty() {
+        _ = write!(body, "<p>Nothing matches “{}”.</p>", escape(query));
+    }
+    body.push_str("<ul>");
+    for hit in &hits {
+        let (title, href) = match hit.kind.as_str() {
+            "digest" => (
+                "Digest".to_string(),
+                Some(format!("/ui/digests/{}", hit.id)),
+            ),
+            "summary" => (
+                "Summary".to_string(),
+                Some(format!("/ui/summaries/{}", hit.id)),
+            ),
+            _ => ("Message".to_string(), hit.jump_url()),
+        };
+        match href {
+            Some(href) => _ = write!(body, "<li><a href=\"{}\">{title}</a>", escape(&href)),
+            None => _ = write!(body, "<li>{title}"),
+        }
+        _ = write!(
+            body,
+            " <span class=\"meta\">{}</span><p>{}</p></li>",
+            format_timestamp(hit.timestamp),
+            highlight_snippet(&hit.snippet)
+        );
+    }
+    body.push_str("</ul>");
+
+    Ok(layout("Search", query, &body))
+}
+
+/// Adds the content security policy to every UI response.
+pub async fn security_headers(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    response
+}
+
+pub async fn login_page() -> Html<String> {
+    login_form(None)
+}
+
+/// Checks the token and, if it may read digests, keeps it in a cookie for the rest of the UI.
+pub async fn login(
+    headers: HeaderMap,
+    Extension(authenticator): Extension<Authenticator>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    if !same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let token = form.token.trim();
+    // Anything else would need escaping to fit in a cookie, and no generated token has it.
+    if token.is_empty()
+        || !token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return login_form(Some("That doesn't look like an API token.")).into_response();
+    }
+
+    match authenticator.check(Some(token), Scope::Read).await {
+        Ok(Access::Granted) => {
+            // Strict, so that no request another site makes the browser send carries the token.
+            let cookie = format!(
+                "{TOKEN_COOKIE}={token}; Path=/ui; HttpOnly; SameSite=Strict; \
+                 Max-Age={TOKEN_COOKIE_MAX_AGE_SECONDS}"
+            );
+            ([(header::SET_COOKIE, cookie)], Redirect::to("/ui")).into_response()
+        }
+        Ok(Access::Forbidden) => login_form(Some("This token can't read digests.")).into_response(),
+        Ok(Access::Unauthorized) => login_form(Some("Unknown or revoked token.")).into_response(),
+        Err(e) => internal_error("API token", e).into_response(),
+    }
+}
+
+pub async fn logout(headers: HeaderMap) -> Response {
+    if !same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let cookie = format!("{TOKEN_COOKIE}=; Path=/ui; HttpOnly; SameSite=Strict; Max-Age=0");
+    ([(header::SET_COOKIE, cookie)], Redirect::to(LOGIN_PATH)).into_response()
+}
+
+/// Whether a form was posted from one of our own pages. Browsers send `Origin` with every POST,
+/// so a mismatch means another site submitted it. Clients that send none aren't browsers.
+fn same_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .map(|(_, host)| host);
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok());
+    origin_host.is_some() && origin_host == host
+}
+
+fn login_form(error: Option<&str>) -> Html<String> {
+    let mut body = String::new();
+    if let Some(error) = error {
+        _ = write!(body, "<p class=\"error\">{}</p>", escape(error));
+    }
+    _ = write!(
+        body,
+        "<form method=\"post\" action=\"{LOGIN_PATH}\">\
+         <p><label>API token <input type=\"password\" name=\"token\" autofocus></label></p>\
+         <p><button type=\"submit\">Log in</button></p>\
+         </form>\
+         <p class=\"meta\">Any token with the <code>read</code> scope works.</p>"
+    );
+    layout("Log in", "", &body)
+}
+
+fn digest_item(
+    body: &mut String,
+    id: i64,
+    rollup: bool,
+    channel_id: Option<i64>,
+    timestamp: NaiveDateTime,
+    text: &str,
+) {
+    _ = write!(
+        body,
+        "<li><a href=\"/ui/digests/{id}\">{}</a> <span class=\"meta\">{}</span><div>{}</div></li>",
+        escape(&digest_title(rollup, channel_id)),
+        format_timestamp(timestamp),
+        escape(&preview(text))
+    );
+}
+
+fn summary_item(body: &mut String, summary: &db::Summary) {
+    _ = write!(
+        body,
+        "<li><a href=\"/ui/summaries/{}\">Summary of {}</a><div>{}</div></li>",
+        summary.id,
+        format_timestamp(summary.timestamp),
+        escape(&preview(&summary.text))
+    );
+}
+
+/// Wraps a page body in the shared header and styles. `search` prefills the search box.
+fn layout(title: &str, search: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{title} · Daily digests</title>
+<style>{STYLE}</style>
+</head>
+<body>
+<header>
+<a href=\"/ui\">Digests</a>
+<form class=\"search\" method=\"get\" action=\"/ui/search\">
+<input type=\"search\" name=\"q\" value=\"{search}\" placeholder=\"Search\">
+</form>
+<form method=\"post\" action=\"/ui/logout\"><button type=\"submit\">Log out</button></form>
+</header>
+<main>
+<h1>{title}</h1>
+{body}
+</main>
+</body>
+</html>
+",
+        title = escape(title),
+        search = escape(search),
+    ))
+}
+
+/// Escapes a search snippet, and bolds the matches search marked with `**`. Snippets come
+/// straight from chat messages, so they are never rendered as markdown.
+fn highlight_snippet(snippet: &str) -> String {
+    let parts: Vec<String> = escape(snippet).split("**").map(str::to_string).collect();
+    let mut highlighted = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        match (i % 2 == 1, i + 1 < parts.len()) {
+            (true, true) => _ = write!(highlighted, "<b>{part}</b>"),
+            // A marker without a closing one is left as it was.
+            (true, false) => _ = write!(highlighted, "**{part}"),
+            (false, _) => highlighted.push_str(part),
+        }
+    }
+    highlighted
+}
+
+/// The start of some markdown, as plain text.
+fn preview(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= PREVIEW_CHARS {
+        return text;
+    }
+    let mut preview: String = text.chars().take(PREVIEW_CHARS).collect();
+    preview.push('…');
+    preview
+}
+
+fn format_timestamp(timestamp: NaiveDateTime) -> String {
+    timestamp.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn internal_error(what: &str, e: sqlx::Error) -> StatusCode {
+    error!("Could not fetch {what}: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_are_escaped_and_highlighted() {
+        assert_eq!(
+            highlight_snippet("see **[x](javascript:alert(1))** <script>"),
+            "see <b>[x](javascript:alert(1))</b> &lt;script&gt;"
+        );
+        assert_eq!(highlight_snippet("a **b** c **d"), "a <b>b</b> c **d");
+    }
+}
@@ -0,0 +1,2 @@
+//! Parts shared with the bundled binaries other than the bot itself.
+pub mod mock_llm;
@@ -2,23 +2,39 @@ use std::collections::HashSet;
 use std::env;
 use std::sync::Arc;
 
-use axum::routing::get;
-use axum::{Extension, Router};
+use axum::routing::{get, post};
+use axum::{middleware, Extension, Router};
+use clap::Parser;
+use cli::{Command, DbCommand};
+use config::Scope;
 use dotenv::dotenv;
 use futures::future::join_all;
+use http_api::auth::{self, Authenticator};
+use http_api::{feeds, ui};
+use serenity::http::Http;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
-use services::digests::DailyRecapService;
+use services::budget::Budget;
+use services::digests::{DailyRecapService, DigestTrigger};
 use services::discord_handler::Handler;
+use services::discord_poster::DigestPoster;
+use services::embeddings::EmbeddingService;
+use services::health::Health;
 use services::message_listener::MessageLogService;
-use services::summarizer::SummarizerService;
+use services::schedule::DigestSchedule;
+use services::summarizer::{batch_tokens, SummarizerService};
+use services::supervisor::Supervisor;
+use sqlx::SqlitePool;
 use tokio::task::{self, JoinError};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
+mod cli;
 mod config;
 mod db;
 mod gpt;
 mod http_api;
+mod metrics;
 mod services;
 
 #[tokio::main]
@@ -27,13 +43,36 @@ async fn main() -> eyre::Result<()> {
 
     tracing_subscriber::fmt::init();
 
-    let token = env::var("DISCORD_BOT_SECRET").expect("No DISCORD_BOT_SECRET provided");
+    let cli = cli::Cli::parse();
     let config = config::AppConfig::load()?;
-    _ = config;
-    let messages_base = config.service.message_log_directory.clone();
 
-    dbg!(&config);
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config.clone(), connect_database(&config).await).await,
+        Command::SummarizeFile { path } => cli::summarize_file(&config, &path).await,
+        Command::DigestNow => cli::digest_now(&config, connect_database(&config).await).await,
+        Command::Recap { channel, since } => {
+            cli::recap(&config, connect_database(&config).await, channel, &since).await
+        }
+        Command::Export { output } => {
+            cli::export(connect_database(&config).await, output.as_deref()).await
+        }
+        Command::Db {
+            command: DbCommand::Migrate,
+        } => {
+            // Connecting runs any pending migrations.
+            connect_database(&config).await;
+            info!("Database is up to date");
+            Ok(())
+        }
+        Command::Token { command } => cli::token(&config, command).await,
+        Command::Replay { channel } => {
+            cli::replay(&config, connect_database(&config).await, channel).await
+        }
+        Command::Embed => cli::embed(&config, connect_database(&config).await).await,
+    }
+}
 
+async fn connect_database(config: &config::AppConfig) -> Arc<SqlitePool> {
     // Initiate a connection to the database file, creating the file if required.
     let database = sqlx::sqlite::SqlitePoolOptions::new()
         .max_connections(4)
@@ -51,69 +90,231 @@ async fn main() -> eyre::Result<()> {
         .await
         .expect("Couldn't run database migrations");
 
-    let shared_db = Arc::new(database);
+    Arc::new(database)
+}
+
+/// Runs the bot until it is stopped.
+async fn serve(config: config::AppConfig, shared_db: Arc<SqlitePool>) -> eyre::Result<()> {
+    let token = env::var("DISCORD_BOT_SECRET").expect("No DISCORD_BOT_SECRET provided");
+
+    // One provider for everything, so that its HTTP client and connections are shared.
+    let provider = gpt::provider_from_config(&config, Some(shared_db.clone()))?;
+    let summarizer = Arc::new(gpt::MapReduceSummarizer::with_provider(
+        provider.clone(),
+        &config,
+    ));
 
     let mut tasks = vec![];
 
+    // Cancelled on SIGTERM or Ctrl+C. Every task then finishes what it is doing and returns.
+    let shutdown = CancellationToken::new();
+    task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            shutdown.cancel();
+        }
+    });
+    // Restarts background services that crash, and shuts everything down if one keeps crashing.
+    let supervisor = Supervisor::from_config(shutdown.clone(), &config.service);
+
     let (summarize_tx, summarize_rx) = tokio::sync::mpsc::channel(100);
     let (discord_tx, discord_rx) = tokio::sync::mpsc::channel(100);
+    // Cancelled once the gateway is closed, so that the message log service stores everything
+    // received until then.
+    let gateway_closed = CancellationToken::new();
 
-    let mut summary_srv =
-        SummarizerService::new(messages_base.clone(), summarize_rx, shared_db.clone());
-    tasks.push(task::spawn(async move {
-        info!("Running summary service");
-        summary_srv.run().await;
+    let health = Health::new(
+        shared_db.clone(),
+        supervisor.clone(),
+        summarize_tx.downgrade(),
+        provider.clone(),
+    );
+
+    let tokenizer = gpt::Tokenizer::from_config(&config);
+    let batch_tokens = batch_tokens(&config, &tokenizer);
+
+    let http = Arc::new(Http::new(&token));
+    // Shared, so that summaries and digests count towards the same monthly budget.
+    let budget = Arc::new(Budget::from_config(
+        shared_db.clone(),
+        http.clone(),
+        &config,
+    ));
+
+    // A restarted service picks up where the crashed one left off, with the same channels.
+    let summary_srv = Arc::new(tokio::sync::Mutex::new(
+        SummarizerService::from_config(
+            summarize_rx,
+            shared_db.clone(),
+            summarizer.clone(),
+            &config,
+        )
+        .with_budget(budget.clone()),
+    ));
+    tasks.push(supervisor.spawn("summary service", move |shutdown| {
+        let summary_srv = summary_srv.clone();
+        async move { summary_srv.lock().await.run(shutdown).await }
     }));
 
-    let mut message_log_srv = MessageLogService::new(
-        messages_base,
+    let message_log_srv = Arc::new(tokio::sync::Mutex::new(MessageLogService::new(
+        shared_db.clone(),
         summarize_tx,
         discord_rx,
-        config.service.max_gpt_request_tokens,
-    );
-    tasks.push(task::spawn(async move {
-        info!("Running message log service");
-        message_log_srv.run().await;
+        batch_tokens,
+        tokenizer,
+        gateway_closed.clone(),
+    )));
+    tasks.push(supervisor.spawn("message log service", move |shutdown| {
+        let message_log_srv = message_log_srv.clone();
+        async move { message_log_srv.lock().await.run(shutdown).await }
     }));
 
-    let mut daily_recap_srv = DailyRecapService::new(
-        shared_db.clone(),
-        config.service.produce_digest_interval_seconds,
-        config.clone(),
-    );
-    tasks.push(task::spawn(async move {
-        info!("Running daily digest service");
-        daily_recap_srv.run().await;
+    let (digest_trigger, digest_trigger_rx) = DigestTrigger::channel();
+    let daily_recap_srv = Arc::new(tokio::sync::Mutex::new(
+        DailyRecapService::new(
+            shared_db.clone(),
+            DigestSchedule::from_config(&config.service)?,
+            digest_trigger_rx,
+            config.clone(),
+            summarizer.clone(),
+            DigestPoster::from_config(http, &config.discord),
+        )
+        .with_budget(budget),
+    ));
+    tasks.push(supervisor.spawn("daily digest service", move |shutdown| {
+        let daily_recap_srv = daily_recap_srv.clone();
+        async move { daily_recap_srv.lock().await.run(shutdown).await }
     }));
 
+    let embedder = gpt::embedding_provider_from_config(&config, Some(shared_db.clone()))?;
+    if let (Some(embedder), Some(embeddings_config)) = (&embedder, &config.embeddings) {
+        let embedding_srv = Arc::new(EmbeddingService::from_config(
+            shared_db.clone(),
+            embedder.clone(),
+            embeddings_config,
+        ));
+        tasks.push(supervisor.spawn("embedding service", move |shutdown| {
+            let embedding_srv = embedding_srv.clone();
+            async move { embedding_srv.run(shutdown).await }
+        }));
+    }
+
     let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
     let mut discord_client = Client::builder(token, intents)
-        .event_handler(Handler::new(
-            discord_tx,
-            HashSet::from_iter(
-                config
-                    .discord
-                    .channel_ids
-                    .iter()
-                    .map(|x| ChannelId::new(x.parse().unwrap())),
-            ),
-        ))
+        .event_handler(
+            Handler::new(
+                discord_tx,
+                HashSet::from_iter(
+                    config
+                        .discord
+                        .channel_ids
+                        .iter()
+                        .map(|x| ChannelId::new(x.parse().unwrap())),
+                ),
+                shared_db.clone(),
+                health.clone(),
+                Arc::new(config.clone()),
+                provider,
+                summarizer,
+            )
+            .with_embedder(embedder.clone()),
+        )
         // .framework(make_framework().await)
         .await
         .expect("Error creating client");
 
-    tasks.push(task::spawn(async move {
-        // The Serenity crate Will automatically attempt to reconnect, and will perform
-        // exponential backoff until it reconnects.
-        if let Err(why) = discord_client.start().await {
-            error!("Client error: {why:?}");
+    let shard_manager = discord_client.shard_manager.clone();
+    tasks.push(task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            // However this ends, nothing more comes from Discord afterwards.
+            let _closed = gateway_closed.drop_guard();
+            tokio::select! {
+                // The Serenity crate Will automatically attempt to reconnect, and will perform
+                // exponential backoff until it reconnects.
+                result = discord_client.start() => {
+                    if let Err(why) = result {
+                        error!("Client error: {why:?}");
+                    }
+                }
+                // Closes the gateway connection cleanly. Without one, `start` would never
+                // return, so it isn't waited on.
+                _ = shutdown.cancelled() => shard_manager.shutdown_all().await,
+            }
         }
     }));
 
-    let app = Router::new()
+    // Every route requires a token with the scope it needs, unless anonymous access is configured.
+    let authenticator = Authenticator::new(shared_db.clone(), config.api.clone());
+    let read_routes = Router::new()
         .route("/summaries", get(http_api::summaries_handler))
+        .route("/summaries/:id", get(http_api::summary_handler))
         .route("/daily_digests", get(http_api::daily_digests_handler))
-        .layer(Extension(shared_db));
+        .route(
+            "/daily_digests/latest",
+            get(http_api::latest_daily_digest_handler),
+        )
+        .route("/daily_digests/:id", get(http_api::daily_digest_handler))
+        .route("/search", get(http_api::search_handler))
+        .route("/metrics", get(http_api::metrics_handler))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.require(Scope::Read),
+            auth::require_scope,
+        ));
+    let feed_routes = Router::new()
+        .route("/feeds/digests.atom", get(feeds::digests_feed_handler))
+        .route(
+            "/feeds/channels/:channel_id/digests.atom",
+            get(feeds::channel_digests_feed_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.require_in_url(Scope::Read),
+            auth::require_scope,
+        ));
+    let ui_routes = Router::new()
+        .route("/ui", get(ui::digests_page))
+        .route("/ui/digests/:id", get(ui::digest_page))
+        .route("/ui/summaries/:id", get(ui::summary_page))
+        .route("/ui/search", get(ui::search_page))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.require_in_cookie(Scope::Read, ui::LOGIN_PATH),
+            auth::require_scope,
+        ))
+        // Logging in and out is how a browser gets and drops its token.
+        .route(ui::LOGIN_PATH, get(ui::login_page).post(ui::login))
+        .route("/ui/logout", post(ui::logout))
+        .layer(middleware::map_response(ui::security_headers));
+    let trigger_routes = Router::new()
+        .route("/daily_digests/run", post(http_api::run_digests_handler))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.require(Scope::Trigger),
+            auth::require_scope,
+        ));
+    let admin_routes = Router::new()
+        .route("/services", get(http_api::services_handler))
+        .route("/health", get(http_api::health_handler))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.require(Scope::Admin),
+            auth::require_scope,
+        ));
+    // Health checks come from orchestrators and load balancers, which have no token.
+    let health_routes = Router::new()
+        .route("/healthz", get(http_api::healthz_handler))
+        .route("/readyz", get(http_api::readyz_handler));
+    let app = read_routes
+        .merge(feed_routes)
+        .merge(ui_routes)
+        .merge(trigger_routes)
+        .merge(admin_routes)
+        .merge(health_routes)
+        .layer(Extension(shared_db.clone()))
+        .layer(Extension(digest_trigger))
+        .layer(Extension(embedder))
+        .layer(Extension(supervisor.clone()))
+        .layer(Extension(health))
+        .layer(Extension(authenticator));
 
     tasks.push(task::spawn(async move {
         info!("Serving http API on port {}", config.service.port);
@@ -123,12 +324,43 @@ async fn main() -> eyre::Result<()> {
         ))
         .await
         .unwrap();
-        axum::serve(listener, app).await.unwrap();
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+            .unwrap();
     }));
 
     join_all(tasks)
         .await
         .into_iter()
         .collect::<Result<Vec<_>, JoinError>>()?;
+    shared_db.close().await;
+    if let Some(failure) = supervisor.failure() {
+        return Err(eyre::eyre!(failure));
+    }
+    info!("Shut down");
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Couldn't listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
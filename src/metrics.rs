@@ -0,0 +1,130 @@
+use std::sync::OnceLock;
+
+use prometheus::core::Collector;
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+const NAMESPACE: &str = "discord_summarizer";
+
+/// LLM requests take anywhere from a fraction of a second to a couple of minutes.
+const LLM_LATENCY_BUCKETS: &[f64] = &[0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0, 160.0];
+
+/// Everything served on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Messages received from the channels we listen to, by channel.
+    pub messages_received: IntCounterVec,
+    /// Batches of a channel's messages handed off to be summarized because they outgrew the
+    /// request token budget, by channel.
+    pub message_batches: IntCounterVec,
+    pub summaries_produced: IntCounter,
+    /// Digests produced, by kind: `channel` or `rollup`.
+    pub digests_produced: IntCounterVec,
+    /// How long each request to the LLM took, by model, whether it succeeded or not.
+    pub llm_request_duration: HistogramVec,
+    /// Failed LLM requests, by model and the kind of failure.
+    pub llm_failures: IntCounterVec,
+    /// Tokens billed by the LLM provider, by model and type: `prompt` or `completion`.
+    pub llm_tokens: IntCounterVec,
+    /// Estimated dollar cost of LLM requests, by model. Only models with a configured price count.
+    pub llm_cost_dollars: CounterVec,
+}
+
+/// The process-wide metrics, registered on first use.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)
+            .expect("Metrics namespace is invalid");
+
+        Self {
+            messages_received: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("messages_received_total", "Messages received from Discord"),
+                    &["channel_id"],
+                ),
+            ),
+            message_batches: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "message_batches_total",
+                        "Batches of messages handed off to be summarized",
+                    ),
+                    &["channel_id"],
+                ),
+            ),
+            summaries_produced: register(
+                &registry,
+                IntCounter::new("summaries_produced_total", "Summaries produced"),
+            ),
+            digests_produced: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("digests_produced_total", "Digests produced"),
+                    &["kind"],
+                ),
+            ),
+            llm_request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("llm_request_duration_seconds", "LLM request latency")
+                        .buckets(LLM_LATENCY_BUCKETS.to_vec()),
+                    &["model"],
+                ),
+            ),
+            llm_failures: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("llm_request_failures_total", "Failed LLM requests"),
+                    &["model", "kind"],
+                ),
+            ),
+            llm_tokens: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("llm_tokens_total", "Tokens billed by the LLM provider"),
+                    &["model", "type"],
+                ),
+            ),
+            llm_cost_dollars: register(
+                &registry,
+                CounterVec::new(
+                    Opts::new(
+                        "llm_cost_dollars_total",
+                        "Estimated cost of LLM requests in dollars",
+                    ),
+                    &["model"],
+                ),
+            ),
+            registry,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Could not encode metrics");
+        String::from_utf8(buffer).expect("Metrics are not UTF-8")
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<T>,
+) -> T {
+    let metric = metric.expect("Metric definition is invalid");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
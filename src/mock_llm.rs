@@ -0,0 +1,133 @@
+//! A stand-in for the OpenAI and Anthropic chat APIs that answers every request with a canned
+//! response, served by the `mock-llm` binary and by tests.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tracing::info;
+
+/// Routes for the OpenAI and Anthropic chat APIs, under `/v1`. Requests are answered with each of
+/// `responses` in turn, or with numbered mock summaries if there are none, after answering the
+/// first `rate_limit_first` requests with `429 Too Many Requests`.
+pub fn router(responses: Vec<String>, rate_limit_first: usize) -> Router {
+    let state = Arc::new(MockState {
+        responses,
+        requests: AtomicUsize::new(0),
+        rate_limit_first,
+    });
+    Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/messages", post(messages))
+        .route("/v1/models", get(models))
+        .with_state(state)
+}
+
+struct MockState {
+    responses: Vec<String>,
+    requests: AtomicUsize,
+    rate_limit_first: usize,
+}
+
+impl MockState {
+    /// Returns the next canned response, or `None` if this request should be rate limited.
+    fn next_response(&self) -> Option<(usize, String)> {
+        let n = self.requests.fetch_add(1, Ordering::SeqCst);
+        if n < self.rate_limit_first {
+            info!("Rate limiting request #{n}");
+            return None;
+        }
+
+        let n = n - self.rate_limit_first;
+        let content = if self.responses.is_empty() {
+            format!("Mock summary #{}", n + 1)
+        } else {
+            self.responses[n % self.responses.len()].clone()
+        };
+        Some((n, content))
+    }
+}
+
+fn rate_limited() -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [("retry-after", "1")],
+        Json(json!({ "error": { "message": "Rate limit reached" } })),
+    )
+        .into_response()
+}
+
+fn token_estimate(text: &str) -> usize {
+    text.chars().count() / 4
+}
+
+async fn chat_completions(
+    State(state): State<Arc<MockState>>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let (n, content) = state.next_response().ok_or_else(rate_limited)?;
+    info!("Serving chat completion #{n}");
+    let prompt_tokens = token_estimate(&body["messages"].to_string());
+    let completion_tokens = token_estimate(&content);
+
+    Ok(Json(json!({
+        "id": format!("chatcmpl-mock-{n}"),
+        "object": "chat.completion",
+        "model": body["model"],
+        "choices": [
+            {
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": content,
+                },
+                "finish_reason": "stop",
+            }
+        ],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    })))
+}
+
+async fn messages(
+    State(state): State<Arc<MockState>>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let (n, content) = state.next_response().ok_or_else(rate_limited)?;
+    info!("Serving message #{n}");
+    let input_tokens = token_estimate(&body["messages"].to_string());
+    let output_tokens = token_estimate(&content);
+
+    Ok(Json(json!({
+        "id": format!("msg_mock_{n}"),
+        "type": "message",
+        "role": "assistant",
+        "model": body["model"],
+        "content": [
+            {
+                "type": "text",
+                "text": content,
+            }
+        ],
+        "stop_reason": "end_turn",
+        "usage": {
+            "input_tokens": input_tokens,
+            "output_tokens": output_tokens,
+        },
+    })))
+}
+
+/// Answers health checks, which list models to see that the API is up.
+async fn models() -> Json<Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{ "id": "mock", "object": "model", "type": "model" }],
+    }))
+}
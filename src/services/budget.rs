@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{Datelike, NaiveDate, Utc};
+use parking_lot::Mutex;
+use serenity::all::{ChannelId, Http};
+use sqlx::SqlitePool;
+use tracing::{error, warn};
+
+use crate::config::{AppConfig, ModelPrice};
+use crate::db::{self, ModelUsage};
+use crate::gpt::Usage;
+
+/// Pauses automatic summarization once the LLM requests of the month, whatever they were for,
+/// have cost more than the monthly budget, and tells the admin channel about it.
+pub struct Budget {
+    db: Arc<SqlitePool>,
+    monthly_limit: Option<f64>,
+    /// Each request is priced by the model it went to, so summaries, digests and embeddings all
+    /// count towards the budget.
+    prices: HashMap<String, ModelPrice>,
+    admin_channel: Option<(Arc<Http>, ChannelId)>,
+    /// The first day of the month admins were last told about, so they are told once a month,
+    /// or once more after a restart.
+    notified_month: Mutex<Option<NaiveDate>>,
+}
+
+impl Budget {
+    pub fn new(
+        db: Arc<SqlitePool>,
+        monthly_limit: Option<f64>,
+        prices: HashMap<String, ModelPrice>,
+        admin_channel: Option<(Arc<Http>, ChannelId)>,
+    ) -> Self {
+        Self {
+            db,
+            monthly_limit,
+            prices,
+            admin_channel,
+            notified_month: Mutex::new(None),
+        }
+    }
+
+    pub fn from_config(db: Arc<SqlitePool>, http: Arc<Http>, config: &AppConfig) -> Self {
+        let monthly_limit = config.summary.monthly_budget;
+        let prices = &config.summary.prices;
+        if monthly_limit.is_some() {
+            let embedding_model = config.embeddings.as_ref().map(|e| &e.model);
+            for model in std::iter::once(&config.summary.model).chain(embedding_model) {
+                if !prices.contains_key(model) {
+                    warn!(
+                        "A monthly budget is set, but model {model} has no price in \
+                         [summary.prices], so its requests don't count towards it"
+                    );
+                }
+            }
+        }
+        let admin_channel = config
+            .discord
+            .admin_channel_id
+            .as_ref()
+            .and_then(|id| id.parse().ok())
+            .map(|id| (http, ChannelId::new(id)));
+        Self::new(db, monthly_limit, prices.clone(), admin_channel)
+    }
+
+    /// Whether this month's budget is used up. If the totals can't be read, carries on as if it
+    /// isn't, rather than stopping summaries over a database hiccup.
+    pub async fn exhausted(&self) -> bool {
+        let Some(limit) = self.monthly_limit else {
+            return false;
+        };
+
+        let month = Utc::now().date_naive().with_day(1).unwrap_or_default();
+        let month_start = month.and_hms_opt(0, 0, 0).unwrap_or_default();
+        let spent = match db::fetch_usage_by_model_since(&self.db, month_start).await {
+            Ok(usage) => usage.iter().map(|usage| self.cost(usage)).sum::<f64>(),
+            Err(e) => {
+                error!("Could not total this month's LLM costs: {e}");
+                return false;
+            }
+        };
+        if spent < limit {
+            return false;
+        }
+
+        let first_notice = self.notified_month.lock().replace(month) != Some(month);
+        if first_notice {
+            let notice = format!(
+                "LLM requests have cost ${spent:.2} this month, over the ${limit:.2} budget. \
+                 Automatic summaries and digests are paused until next month."
+            );
+            warn!("{notice}");
+            self.notify(&notice).await;
+        }
+        true
+    }
+
+    /// What a model's requests cost at its current price, or as estimated when they were made if
+    /// it no longer has one.
+    fn cost(&self, usage: &ModelUsage) -> f64 {
+        match self.prices.get(&usage.model) {
+            Some(price) => Usage {
+                prompt_tokens: usage.prompt_tokens as u64,
+                completion_tokens: usage.completion_tokens as u64,
+            }
+            .cost(price),
+            None => usage.cost.unwrap_or_default(),
+        }
+    }
+
+    async fn notify(&self, notice: &str) {
+        let Some((http, channel)) = &self.admin_channel else {
+            return;
+        };
+        if let Err(e) = channel.say(http, notice).await {
+            error!("Could not notify the admin channel: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::db::tests::memory_pool;
+    use crate::db::LlmUsage;
+
+    async fn record(db: &SqlitePool, model: &str, prompt_tokens: i64, cost: Option<f64>) {
+        let usage = LlmUsage {
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens: 0,
+            latency_ms: 1,
+            cost,
+        };
+        db::insert_llm_usage(db, &usage).await.unwrap();
+    }
+
+    fn prices() -> HashMap<String, ModelPrice> {
+        HashMap::from([
+            (
+                "gpt-4".to_string(),
+                ModelPrice {
+                    prompt: 1.0,
+                    completion: 2.0,
+                },
+            ),
+            (
+                "embedder".to_string(),
+                ModelPrice {
+                    prompt: 0.5,
+                    completion: 0.0,
+                },
+            ),
+        ])
+    }
+
+    /// A budget of a dollar that this month's requests in `db` have already used up.
+    pub(crate) async fn exhausted_budget(db: Arc<SqlitePool>) -> Arc<Budget> {
+        record(&db, "gpt-4", 2_000_000, None).await;
+        Arc::new(Budget::new(db, Some(1.0), prices(), None))
+    }
+
+    #[tokio::test]
+    async fn prices_each_request_by_its_own_model() {
+        let db = Arc::new(memory_pool().await);
+        // 60 cents of summaries and 50 of embeddings, priced now rather than when recorded.
+        record(&db, "gpt-4", 600_000, None).await;
+        let budget = Budget::new(db.clone(), Some(1.0), prices(), None);
+        assert!(!budget.exhausted().await);
+        record(&db, "embedder", 1_000_000, Some(0.0)).await;
+        assert!(budget.exhausted().await);
+
+        // Models without a price count at what was estimated when the request was made.
+        let db = Arc::new(memory_pool().await);
+        record(&db, "retired", 1, Some(0.5)).await;
+        let budget = Budget::new(db.clone(), Some(1.0), prices(), None);
+        assert!(!budget.exhausted().await);
+        record(&db, "retired", 1, Some(0.5)).await;
+        assert!(budget.exhausted().await);
+    }
+
+    #[tokio::test]
+    async fn notifies_once_a_month() {
+        let db = Arc::new(memory_pool().await);
+        let budget = exhausted_budget(db).await;
+        let month = Utc::now().date_naive().with_day(1).unwrap();
+
+        assert!(budget.exhausted().await);
+        assert_eq!(*budget.notified_month.lock(), Some(month));
+        assert!(budget.exhausted().await);
+        assert_eq!(*budget.notified_month.lock(), Some(month));
+
+        // Told last month, so told again now.
+        let last_month = month.pred_opt().unwrap().with_day(1).unwrap();
+        *budget.notified_month.lock() = Some(last_month);
+        assert!(budget.exhausted().await);
+        assert_eq!(*budget.notified_month.lock(), Some(month));
+    }
+
+    #[tokio::test]
+    async fn last_months_requests_and_missing_limits_are_ignored() {
+        let db = Arc::new(memory_pool().await);
+        record(&db, "gpt-4", 2_000_000, None).await;
+        sqlx::query("UPDATE llm_usage SET timestamp = datetime('now', 'start of month', '-1 day')")
+            .execute(&*db)
+            .await
+            .unwrap();
+        let budget = Budget::new(db.clone(), Some(1.0), prices(), None);
+        assert!(!budget.exhausted().await);
+
+        let unlimited = exhausted_budget(db).await;
+        let unlimited = Budget::new(unlimited.db.clone(), None, prices(), None);
+        assert!(!unlimited.exhausted().await);
+        assert!(budget.exhausted().await);
+    }
+}
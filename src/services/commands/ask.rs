@@ -0,0 +1,186 @@
+use serenity::builder::*;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use sqlx::SqlitePool;
+use tracing::{error, info, warn};
+
+use crate::config::AppConfig;
+use crate::db;
+use crate::gpt::{EmbeddingProvider, SummaryProvider};
+use crate::services::discord_poster::{split_message, MESSAGE_CHAR_LIMIT};
+use crate::services::embeddings::semantic_search;
+
+/// How many of the best search hits are given to the LLM to answer from.
+const MAX_SOURCES: i64 = 8;
+
+/// A search hit with the full text it stands for.
+struct Source {
+    hit: db::SearchHit,
+    text: String,
+}
+
+pub async fn run(
+    ctx: &Context,
+    interaction: &CommandInteraction,
+    db: &SqlitePool,
+    config: &AppConfig,
+    provider: &dyn SummaryProvider,
+    embedder: Option<&dyn EmbeddingProvider>,
+) -> Result<Option<String>, serenity::Error> {
+    let options = interaction.data.options();
+    let question = options
+        .iter()
+        .find(|opt| opt.name == "question")
+        .and_then(|opt| match opt.value {
+            ResolvedValue::String(val) => Some(val.to_string()),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let data = CreateInteractionResponseMessage::new()
+        .content("Looking through the channel history, this might take a few seconds...")
+        .ephemeral(true);
+    interaction
+        .create_response(&ctx.http, CreateInteractionResponse::Message(data))
+        .await?;
+
+    // Only answer from the channel the command was used in, which everyone asking can read.
+    let channel_id = interaction.channel_id.get() as i64;
+    info!("Answering {question:?} from channel {channel_id}");
+    let content = match find_sources(db, embedder, &question, channel_id).await {
+        Ok(sources) if sources.is_empty() => {
+            "I couldn't find anything about that in this channel.".to_string()
+        }
+        Ok(sources) => match answer(config, provider, &question, &sources).await {
+            Some(answer) => with_citations(&answer, &sources),
+            None => "Could not answer the question, please try again later.".to_string(),
+        },
+        Err(e) => {
+            error!("Could not find sources for {question:?}: {e}");
+            "Could not search the channel history, please try again later.".to_string()
+        }
+    };
+
+    for part in split_message(&content, MESSAGE_CHAR_LIMIT) {
+        let data = CreateInteractionResponseFollowup::new()
+            .content(part)
+            .ephemeral(true);
+        interaction.create_followup(&ctx.http, data).await?;
+    }
+    Ok(Some("Command processed".to_string()))
+}
+
+/// The stored messages, summaries and digests of the channel that best match the question,
+/// best first. Matches by meaning if embeddings are configured, and by words otherwise.
+async fn find_sources(
+    db: &SqlitePool,
+    embedder: Option<&dyn EmbeddingProvider>,
+    question: &str,
+    channel_id: i64,
+) -> Result<Vec<Source>, sqlx::Error> {
+    let hits = match semantic_hits(db, embedder, question, channel_id).await {
+        // Nothing may be embedded yet, e.g. right after embeddings were turned on.
+        Some(hits) if !hits.is_empty() => hits,
+        _ => db::search_any(db, question, Some(channel_id), None, MAX_SOURCES).await?,
+    };
+
+    let mut sources = vec![];
+    for hit in hits {
+        let text = match hit.kind.as_str() {
+            "digest" => db::fetch_daily_digest(db, hit.id).await?.map(|d| d.text),
+            "summary" => db::fetch_summary(db, hit.id).await?.map(|s| s.text),
+            _ => db::fetch_message(db, hit.id)
+                .await?
+                .map(|m| m.prompt_line()),
+        };
+        if let Some(text) = text {
+            sources.push(Source { hit, text });
+        }
+    }
+    Ok(sources)
+}
+
+/// Semantic search hits for the question, or `None` if embeddings are not configured or failed.
+async fn semantic_hits(
+    db: &SqlitePool,
+    embedder: Option<&dyn EmbeddingProvider>,
+    question: &str,
+    channel_id: i64,
+) -> Option<Vec<db::SearchHit>> {
+    let embedder = embedder?;
+    match semantic_search(db, embedder, question, Some(channel_id), None, MAX_SOURCES).await {
+        Ok(hits) => Some(hits),
+        Err(e) => {
+            warn!("Semantic search failed, searching by words instead: {e}");
+            None
+        }
+    }
+}
+
+async fn answer(
+    config: &AppConfig,
+    provider: &dyn SummaryProvider,
+    question: &str,
+    sources: &[Source],
+) -> Option<String> {
+    // The question goes first, so that if the sources don't all fit, the worst ones are cut.
+    let mut input = format!("Question: {question}\n\nSources:\n");
+    for (n, source) in sources.iter().enumerate() {
+        input.push_str(&format!(
+            "[{}] {} from {}:\n{}\n\n",
+            n + 1,
+            source.hit.kind,
+            source.hit.timestamp,
+            source.text
+        ));
+    }
+
+    match provider.summarize(&config.summary.ask_prompt, &input).await {
+        Ok(answer) => Some(answer.text),
+        Err(e) => {
+            error!("Could not answer {question:?}: {e}");
+            None
+        }
+    }
+}
+
+/// The answer followed by links to the sources it cites, or to every source if it cites none.
+fn with_citations(answer: &str, sources: &[Source]) -> String {
+    let numbered = sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| (i + 1, source));
+    let mut cited: Vec<_> = numbered
+        .clone()
+        .filter(|(n, _)| answer.contains(&format!("[{n}]")))
+        .collect();
+    if cited.is_empty() {
+        cited = numbered.collect();
+    }
+
+    let mut content = format!("{}\n\n**Sources**", answer.trim());
+    for (n, source) in cited {
+        let kind = match source.hit.kind.as_str() {
+            "digest" => "Digest",
+            "summary" => "Summary",
+            _ => "Message",
+        };
+        content.push_str(&format!(
+            "\n[{n}] {kind} · <t:{}:d>",
+            source.hit.timestamp.and_utc().timestamp()
+        ));
+        if let Some(url) = source.hit.jump_url() {
+            content.push_str(&format!(" · [Jump]({url})"));
+        }
+    }
+    content
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("ask")
+        .description("Ask a question about the channel's history")
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "question", "What to ask")
+                .required(true),
+        )
+}
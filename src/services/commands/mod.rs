@@ -1 +1,3 @@
+pub mod ask;
 pub mod recap;
+pub mod search;
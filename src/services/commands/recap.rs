@@ -10,7 +10,7 @@ use serenity::prelude::*;
 use tracing::{error, info};
 
 use crate::config::AppConfig;
-use crate::gpt::SummaryConfig;
+use crate::gpt::MapReduceSummarizer;
 
 #[derive(Debug)]
 struct SimpleMessage {
@@ -49,13 +49,28 @@ impl Timeframe {
             }
         }
     }
+
+    fn start(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        match self {
+            Timeframe::LastDay => now - Duration::days(1),
+            Timeframe::LastWeek => now - Duration::weeks(1),
+            Timeframe::LastMonth => now - Duration::weeks(4),
+            Timeframe::Custom(date) => *date,
+        }
+    }
+}
+
+/// Parses a `since` value the way the recap command does: `last_day`, `last_week`,
+/// `last_month`, a duration like `3 days`, or a date.
+pub fn parse_since(s: &str) -> Option<DateTime<Utc>> {
+    Timeframe::from_str(s).map(|timeframe| timeframe.start())
 }
 
 async fn get_guild_id_from_channel(ctx: &Context, channel_id: ChannelId) -> Option<GuildId> {
-    if let Ok(channel) = channel_id.to_channel(ctx).await {
-        if let serenity::model::channel::Channel::Guild(channel) = channel {
-            return Some(channel.guild_id);
-        }
+    if let Ok(serenity::model::channel::Channel::Guild(channel)) = channel_id.to_channel(ctx).await
+    {
+        return Some(channel.guild_id);
     }
     None
 }
@@ -153,13 +168,15 @@ async fn get_recent_messages(
         info!("Last message id: {:?}", last_message_id);
     }
 
-    messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    messages.sort_by_key(|msg| msg.timestamp);
     Ok(messages)
 }
 
 pub async fn run(
     ctx: &Context,
     interaction: &CommandInteraction,
+    config: &AppConfig,
+    summarizer: &MapReduceSummarizer,
 ) -> Result<Option<String>, serenity::Error> {
     let channel_id = interaction.channel_id;
     let options = interaction.data.options();
@@ -238,7 +255,6 @@ pub async fn run(
                 Timeframe::Custom(date) => get_recent_messages(ctx, channel_id, date).await?,
             };
 
-            let config = AppConfig::load().unwrap();
             let formatted_messages: Vec<String> = messages
                 .iter()
                 .map(|msg| {
@@ -250,20 +266,11 @@ pub async fn run(
                     )
                 })
                 .collect();
-            let file_contents = formatted_messages.join("\n");
-
-            match crate::gpt::summarize(
-                &file_contents,
-                SummaryConfig {
-                    max_tokens: config.summary.max_tokens,
-                    model: config.summary.model.to_string(),
-                    prompt: config.summary.prompt.to_string(),
-                    ..SummaryConfig::default()
-                },
-            )
-            .await
+            match summarizer
+                .summarize(&config.summary.prompt, &formatted_messages)
+                .await
             {
-                Ok(txt) => Some(txt),
+                Ok(summarized) => Some(summarized.text),
                 Err(e) => {
                     error!("Could not summarize message log: {e}");
                     None
@@ -321,12 +328,14 @@ pub async fn publish(ctx: &Context, interaction: &Interaction) -> Result<(), ser
             Ok(message) => Ok(message),
             Err(e) => {
                 eprintln!("Failed to create follow-up: {:?}", e);
-                Err(e.into())
+                Err(e)
             }
         }
     } else {
         eprintln!("Interaction is not a message component");
-        Err(serenity::Error::Other("Interaction is not a message component").into())
+        Err(serenity::Error::Other(
+            "Interaction is not a message component",
+        ))
     }
 }
 
@@ -0,0 +1,128 @@
+use serenity::builder::*;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use sqlx::SqlitePool;
+use tracing::{error, info};
+
+use crate::db;
+use crate::services::discord_poster::MESSAGE_CHAR_LIMIT;
+
+/// How many results to show, as long as they fit in one reply.
+const MAX_RESULTS: i64 = 5;
+const MAX_SNIPPET_CHARS: usize = 300;
+
+pub async fn run(
+    ctx: &Context,
+    interaction: &CommandInteraction,
+    db: &SqlitePool,
+) -> Result<Option<String>, serenity::Error> {
+    let options = interaction.data.options();
+    let query = options
+        .iter()
+        .find(|opt| opt.name == "query")
+        .and_then(|opt| match opt.value {
+            ResolvedValue::String(val) => Some(val.to_string()),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    // Only search the server the command was used in, so no server sees another's messages.
+    let content = match interaction.guild_id {
+        Some(guild_id) => {
+            info!("Searching guild {guild_id} for {query:?}");
+            match db::search(db, &query, None, Some(guild_id.get() as i64), MAX_RESULTS).await {
+                Ok(hits) if hits.is_empty() => format!("Nothing matches “{query}”."),
+                Ok(hits) => format_hits(&hits),
+                Err(e) => {
+                    error!("Could not search for {query:?}: {e}");
+                    "Search failed, please try again later.".to_string()
+                }
+            }
+        }
+        None => "Search only works in a server.".to_string(),
+    };
+
+    let data = CreateInteractionResponseMessage::new()
+        .content(content)
+        .ephemeral(true);
+    interaction
+        .create_response(&ctx.http, CreateInteractionResponse::Message(data))
+        .await?;
+    Ok(Some("Command processed".to_string()))
+}
+
+/// Lists as many of the hits as fit in one Discord message.
+fn format_hits(hits: &[db::SearchHit]) -> String {
+    let mut content = String::new();
+    let mut content_chars = 0;
+    for (n, hit) in hits.iter().enumerate() {
+        let kind = match hit.kind.as_str() {
+            "digest" => "Digest",
+            "summary" => "Summary",
+            _ => "Message",
+        };
+        let mut entry = format!(
+            "**{}. {kind}** · <t:{}:d>",
+            n + 1,
+            hit.timestamp.and_utc().timestamp()
+        );
+        if let Some(url) = hit.jump_url() {
+            entry.push_str(&format!(" · [Jump]({url})"));
+        }
+
+        let snippet: String = hit
+            .snippet
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(MAX_SNIPPET_CHARS)
+            .collect();
+        entry.push_str(&format!("\n> {snippet}\n"));
+
+        let entry_chars = entry.chars().count();
+        if content_chars + entry_chars > MESSAGE_CHAR_LIMIT {
+            break;
+        }
+        content.push_str(&entry);
+        content_chars += entry_chars;
+    }
+    content
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("search")
+        .description("Search past messages, summaries and digests")
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "query", "What to look for")
+                .required(true),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    #[test]
+    fn hits_fit_in_one_message() {
+        let hits: Vec<db::SearchHit> = (0..MAX_RESULTS)
+            .map(|id| db::SearchHit {
+                kind: "message".to_string(),
+                id,
+                channel_id: Some(1_234_567_890_123_456_789),
+                guild_id: Some(1_234_567_890_123_456_789),
+                timestamp: NaiveDateTime::default(),
+                snippet: "word ".repeat(MAX_SNIPPET_CHARS),
+                rank: 0.0,
+                message_id: Some(1_234_567_890_123_456_789),
+            })
+            .collect();
+
+        let content = format_hits(&hits);
+        assert!(content.chars().count() <= MESSAGE_CHAR_LIMIT);
+        assert!(content.starts_with("**1. Message**"));
+        assert!(!content.contains(&format!("**{MAX_RESULTS}. ")));
+    }
+}
@@ -1,93 +1,400 @@
-use crate::{
-    config::AppConfig,
-    db,
-    gpt::{self, SummaryConfig},
-};
+use crate::{config::AppConfig, db, gpt::MapReduceSummarizer, metrics::metrics};
 
-use chrono::NaiveDateTime;
+use super::{budget::Budget, discord_poster::DigestPoster, schedule::DigestSchedule};
+
+use chrono::Utc;
 use sqlx::sqlite::SqlitePool;
-use std::{sync::Arc, time::Duration};
-use tokio::time::interval;
-use tracing::{error, info};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// Asks a running [`DailyRecapService`] to produce digests now.
+#[derive(Clone)]
+pub struct DigestTrigger(Sender<()>);
+
+impl DigestTrigger {
+    pub fn channel() -> (Self, Receiver<()>) {
+        // A run requested while another is pending would produce nothing new.
+        let (tx, rx) = mpsc::channel(1);
+        (Self(tx), rx)
+    }
+
+    /// Requests a run, returning false if the service is no longer running.
+    pub fn trigger(&self) -> bool {
+        !matches!(self.0.try_send(()), Err(TrySendError::Closed(_)))
+    }
+}
 
 pub struct DailyRecapService {
     db: Arc<SqlitePool>,
-    interval: Duration,
+    schedule: DigestSchedule,
+    trigger_rx: Receiver<()>,
     config: AppConfig,
+    summarizer: Arc<MapReduceSummarizer>,
+    poster: Option<DigestPoster>,
+    /// Scheduled runs are skipped while this is exhausted.
+    budget: Option<Arc<Budget>>,
 }
 
 impl DailyRecapService {
-    pub fn new(db: Arc<SqlitePool>, interval_seconds: u64, config: AppConfig) -> Self {
+    pub fn new(
+        db: Arc<SqlitePool>,
+        schedule: DigestSchedule,
+        trigger_rx: Receiver<()>,
+        config: AppConfig,
+        summarizer: Arc<MapReduceSummarizer>,
+        poster: Option<DigestPoster>,
+    ) -> Self {
         Self {
             db,
-            interval: Duration::from_secs(interval_seconds),
+            schedule,
+            trigger_rx,
             config,
+            summarizer,
+            poster,
+            budget: None,
         }
     }
 
-    pub async fn run(&mut self) {
-        let mut interval_timer = interval(self.interval);
+    /// Skips scheduled runs while `budget` is exhausted. Requested runs still happen.
+    pub fn with_budget(mut self, budget: Arc<Budget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
 
-        loop {
-            interval_timer.tick().await;
-            // Perform your task here
-            info!("Running daily recap of summaries...");
+    /// Produces digests on schedule and on request until `shutdown` is cancelled. A run in
+    /// progress is finished first.
+    pub async fn run(&mut self, shutdown: CancellationToken) {
+        // Schedule relative to the last run rather than to process start, so that a restart
+        // neither resets the clock nor skips a run: if a run was missed while we were down, the
+        // next run is already due and happens right away. Runs are recorded whether or not they
+        // produce a digest, so one that had nothing to digest isn't caught up on again.
+        let mut next_run = match db::fetch_last_digest_run(&self.db).await {
+            Ok(Some(last)) => self.schedule.next_after(last.and_utc()),
+            Ok(None) => Some(Utc::now()),
+            Err(e) => {
+                error!("Could not fetch last digest run, scheduling from now: {e}");
+                self.schedule.next_after(Utc::now())
+            }
+        };
 
-            // Here, we should decide whether to fetch all summaries or only those after the last recap.
-            let last_recap: Option<(i32, NaiveDateTime)> =
-                sqlx::query_as::<_, (i32, NaiveDateTime)>(
-                    "SELECT id, timestamp FROM daily_digests ORDER BY timestamp DESC LIMIT 1",
-                )
-                .fetch_optional(&*self.db)
-                .await
-                .unwrap(); // Handle this error properly in production code
-
-            let summaries = match last_recap {
-                Some((_, last_timestamp)) => sqlx::query_as!(
-                    db::Summary,
-                    "SELECT * FROM summaries WHERE timestamp >= ? ORDER BY timestamp ASC",
-                    last_timestamp,
-                )
-                .fetch_all(&*self.db)
-                .await
-                .unwrap(),
-                None => sqlx::query_as!(db::Summary, "SELECT * FROM summaries")
-                    .fetch_all(&*self.db)
-                    .await
-                    .unwrap(),
-            };
+        while let Some(run_at) = next_run {
+            let now = Utc::now();
+            if run_at > now {
+                info!("Next daily recap scheduled at {run_at}");
+                tokio::select! {
+                    _ = tokio::time::sleep((run_at - now).to_std().unwrap_or_default()) => {}
+                    Some(()) = self.trigger_rx.recv() => {
+                        // An extra run; the scheduled one still happens as planned.
+                        info!("Running requested daily recap of summaries...");
+                        self.produce_digests().await;
+                        continue;
+                    }
+                    _ = shutdown.cancelled() => return,
+                }
+            } else if run_at < now {
+                info!("Catching up on daily recap missed at {run_at}");
+            }
 
-            if summaries.is_empty() {
-                info!("No summaries to recap");
-                continue;
+            if self.budget_exhausted().await {
+                // The summaries wait for the first run after the budget allows it again.
+                warn!("Skipping scheduled daily recap, the monthly budget is exhausted");
+            } else {
+                info!("Running daily recap of summaries...");
+                self.produce_digests().await;
+            }
+            if let Err(e) = db::insert_digest_run(&self.db, run_at.naive_utc()).await {
+                error!("Could not record daily recap run: {e}");
+            }
+            next_run = self.schedule.next_after(run_at.max(Utc::now()));
+        }
+        warn!("Digest schedule has no upcoming runs, stopping daily recap service");
+    }
+
+    async fn budget_exhausted(&self) -> bool {
+        match &self.budget {
+            Some(budget) => budget.exhausted().await,
+            None => false,
+        }
+    }
+
+    /// Produces a digest for every channel with summaries not yet in a digest, then optionally
+    /// rolls those digests up into a single digest per guild.
+    pub async fn produce_digests(&self) {
+        let summaries_by_channel = match db::fetch_undigested_summaries_by_channel(&self.db).await {
+            Ok(summaries) => summaries,
+            Err(e) => {
+                error!("Could not fetch summaries to recap: {e}");
+                return;
             }
+        };
+
+        if summaries_by_channel.is_empty() {
+            info!("No summaries to recap");
+            return;
+        }
+
+        let mut digests_by_guild: BTreeMap<Option<i64>, Vec<String>> = BTreeMap::new();
+        for (channel_id, summaries) in summaries_by_channel {
+            let guild_id = summaries.iter().find_map(|s| s.guild_id);
             let summary_ids: Vec<i64> = summaries.iter().map(|s| s.id).collect();
 
             let summaries_content: Vec<String> = summaries.into_iter().map(|s| s.text).collect();
-            let summaries_content = summaries_content.join(" ");
-            let digest = match gpt::summarize(
-                &summaries_content,
-                SummaryConfig {
-                    max_tokens: self.config.summary.max_tokens,
-                    model: self.config.summary.model.to_string(),
-                    prompt: self.config.summary.prompt.to_string(),
-                    ..SummaryConfig::default()
-                },
+            let digest = match self
+                .summarizer
+                .summarize(&self.config.summary.prompt, &summaries_content)
+                .await
+            {
+                Ok(summarized) => summarized,
+                Err(e) => {
+                    error!("Could not summarize daily digest for channel {channel_id:?}: {e}");
+                    continue;
+                }
+            };
+            info!(
+                "Obtained a summarized daily digest for channel {channel_id:?}: {}",
+                digest.text
+            );
+            let digest_id = match db::insert_daily_digest(
+                &self.db,
+                digest.text.clone(),
+                channel_id,
+                guild_id,
+                summary_ids,
+                &digest.usage,
             )
             .await
             {
-                Ok(txt) => txt,
+                Ok(id) => id,
                 Err(e) => {
-                    error!("Could not summarize daily digest: {e}");
+                    error!("Could not insert summarized daily digest into DB: {e}");
                     continue;
                 }
             };
-            info!("Obtained a summarized daily digest: {digest}");
-            if let Err(e) = db::insert_daily_digest(&self.db, digest, summary_ids).await {
-                error!("Could not insert summarized daily digest into DB: {e}");
+            info!("Saved daily digest for channel {channel_id:?} to DB");
+            metrics()
+                .digests_produced
+                .with_label_values(&["channel"])
+                .inc();
+            self.post_digest(digest_id, channel_id, &digest.text).await;
+
+            digests_by_guild
+                .entry(guild_id)
+                .or_default()
+                .push(digest.text);
+        }
+
+        if !self.config.service.digest_rollup {
+            return;
+        }
+
+        for (guild_id, digests) in digests_by_guild {
+            // A single channel's digest already is the roll-up.
+            if digests.len() < 2 {
                 continue;
             }
-            info!("Saved daily digest to DB");
+
+            let rollup = match self
+                .summarizer
+                .summarize(&self.config.summary.prompt, &digests)
+                .await
+            {
+                Ok(summarized) => summarized,
+                Err(e) => {
+                    error!("Could not summarize roll-up digest for guild {guild_id:?}: {e}");
+                    continue;
+                }
+            };
+            let digest_id = match db::insert_rollup_digest(
+                &self.db,
+                rollup.text.clone(),
+                guild_id,
+                &rollup.usage,
+            )
+            .await
+            {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("Could not insert roll-up digest into DB: {e}");
+                    continue;
+                }
+            };
+            info!("Saved roll-up digest for guild {guild_id:?} to DB");
+            metrics()
+                .digests_produced
+                .with_label_values(&["rollup"])
+                .inc();
+            self.post_digest(digest_id, None, &rollup.text).await;
+        }
+    }
+
+    /// Posts a saved digest to Discord, if a digest channel is configured for it, and records
+    /// the message it was posted as.
+    async fn post_digest(&self, digest_id: i64, channel_id: Option<i64>, text: &str) {
+        let Some(poster) = &self.poster else {
+            return;
+        };
+
+        match poster.post(channel_id, text).await {
+            Ok(Some(message_id)) => {
+                if let Err(e) =
+                    db::set_digest_discord_message_id(&self.db, digest_id, message_id.get() as i64)
+                        .await
+                {
+                    error!("Could not record Discord message of digest {digest_id}: {e}");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("Could not post digest {digest_id} to Discord: {e}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::db::tests::{memory_pool, summarized_message};
+    use crate::gpt::tests::mock_llm_config;
+    use crate::services::budget::tests::exhausted_budget;
+
+    /// A service summarizing with the mock LLM, answering with `responses` in turn.
+    async fn service(
+        responses: &[&str],
+        digest_rollup: bool,
+    ) -> (DailyRecapService, DigestTrigger) {
+        let mut config = mock_llm_config(responses, 0).await;
+        config.service.digest_rollup = digest_rollup;
+        let db = Arc::new(memory_pool().await);
+        let summarizer =
+            Arc::new(MapReduceSummarizer::from_config(&config, Some(db.clone())).unwrap());
+        let (trigger, trigger_rx) = DigestTrigger::channel();
+        let service = DailyRecapService::new(
+            db,
+            DigestSchedule::from_config(&config.service).unwrap(),
+            trigger_rx,
+            config,
+            summarizer,
+            None,
+        );
+        (service, trigger)
+    }
+
+    async fn wait_for_digests(db: &SqlitePool, count: usize) -> Vec<db::DailyDigest> {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let digests = db::fetch_daily_digests(db).await.unwrap();
+                if digests.len() >= count {
+                    return digests;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the digests were never produced")
+    }
+
+    #[tokio::test]
+    async fn produces_a_digest_per_channel_and_a_rollup() {
+        let (service, _trigger) = service(&["Ten", "Twenty", "All"], true).await;
+        let db = service.db.clone();
+        for (id, channel_id) in [(1, 10), (2, 10), (3, 20)] {
+            summarized_message(&db, id, channel_id).await;
+        }
+
+        service.produce_digests().await;
+        let digests = db::fetch_daily_digests(&db).await.unwrap();
+        let kinds: Vec<(Option<i64>, bool, usize)> = digests
+            .iter()
+            .map(|d| (d.channel_id, d.rollup, d.summaries.len()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(Some(10), false, 2), (Some(20), false, 1), (None, true, 0)]
+        );
+        let texts: Vec<&str> = digests.iter().map(|d| d.text.as_str()).collect();
+        assert_eq!(texts, vec!["Ten", "Twenty", "All"]);
+
+        // Everything is digested now, so another run has nothing to do.
+        service.produce_digests().await;
+        assert_eq!(db::fetch_daily_digests(&db).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn run_catches_up_then_runs_on_request() {
+        let (mut service, trigger) = service(&[], false).await;
+        let db = service.db.clone();
+        summarized_message(&db, 1, 10).await;
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { service.run(shutdown).await }
+        });
+
+        // Without an earlier run, the first one is due right away.
+        let digests = wait_for_digests(&db, 1).await;
+        assert_eq!(digests[0].channel_id, Some(10));
+
+        summarized_message(&db, 2, 20).await;
+        // A request made while the first run is still going may be dropped, so keep asking.
+        let digests = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                assert!(trigger.trigger());
+                let digests = db::fetch_daily_digests(&db).await.unwrap();
+                if digests.len() >= 2 {
+                    return digests;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the requested digest was never produced");
+        assert_eq!(digests[1].channel_id, Some(20));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(10), running)
+            .await
+            .expect("the service did not stop")
+            .unwrap();
+        // Only the scheduled run is recorded; requested ones leave the schedule alone.
+        assert!(db::fetch_last_digest_run(&db).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn scheduled_runs_are_skipped_while_the_budget_is_exhausted() {
+        let (service, trigger) = service(&[], false).await;
+        let db = service.db.clone();
+        let mut service = service.with_budget(exhausted_budget(db.clone()).await);
+        summarized_message(&db, 1, 10).await;
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { service.run(shutdown).await }
+        });
+
+        // The run due right away is recorded, but produces nothing.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while db::fetch_last_digest_run(&db).await.unwrap().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the scheduled run never happened");
+        assert!(db::fetch_daily_digests(&db).await.unwrap().is_empty());
+
+        // Requested runs still happen.
+        assert!(trigger.trigger());
+        let digests = wait_for_digests(&db, 1).await;
+        assert_eq!(digests[0].channel_id, Some(10));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(10), running)
+            .await
+            .expect("the service did not stop")
+            .unwrap();
+    }
+}
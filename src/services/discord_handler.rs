@@ -1,30 +1,104 @@
 use futures::stream::{self, StreamExt};
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use axum::async_trait;
-use serenity::all::Interaction;
+use serenity::all::{
+    ConnectionStage, GuildId, Interaction, MessageId, MessageUpdateEvent, ShardStageUpdateEvent,
+    Timestamp,
+};
 use serenity::{
     all::{ChannelId, Message, Ready},
     client::{Context, EventHandler},
 };
 
+use sqlx::SqlitePool;
 use tokio::sync::mpsc::Sender;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use super::health::Health;
+use crate::config::AppConfig;
+use crate::gpt::{EmbeddingProvider, MapReduceSummarizer, SummaryProvider};
+use crate::metrics::metrics;
 
 pub enum DiscordMessage {
-    Received(Message),
+    Received(Box<Message>),
+    Edited {
+        id: MessageId,
+        content: String,
+        edited_at: Option<Timestamp>,
+    },
+    Deleted(Vec<MessageId>),
 }
 
 pub struct Handler {
     tx: Sender<DiscordMessage>,
     allowed_channels: HashSet<ChannelId>,
+    db: Arc<SqlitePool>,
+    health: Health,
+    config: Arc<AppConfig>,
+    /// Shared with the summary and digest services, rather than built for every command.
+    provider: Arc<dyn SummaryProvider>,
+    summarizer: Arc<MapReduceSummarizer>,
+    /// The one the HTTP API searches with, if embeddings are configured.
+    embedder: Option<Arc<dyn EmbeddingProvider>>,
 }
 
 impl Handler {
-    pub fn new(tx: Sender<DiscordMessage>, allowed_channels: HashSet<ChannelId>) -> Self {
+    pub fn new(
+        tx: Sender<DiscordMessage>,
+        allowed_channels: HashSet<ChannelId>,
+        db: Arc<SqlitePool>,
+        health: Health,
+        config: Arc<AppConfig>,
+        provider: Arc<dyn SummaryProvider>,
+        summarizer: Arc<MapReduceSummarizer>,
+    ) -> Self {
         Self {
             tx,
             allowed_channels,
+            db,
+            health,
+            config,
+            provider,
+            summarizer,
+            embedder: None,
+        }
+    }
+
+    /// Lets `/ask` find sources by meaning, not just by words.
+    pub fn with_embedder(mut self, embedder: Option<Arc<dyn EmbeddingProvider>>) -> Self {
+        self.embedder = embedder;
+        self
+    }
+
+    /// Passes an edit of a message in a listened-to channel on to be stored.
+    async fn forward_edit(&self, event: MessageUpdateEvent) {
+        if !self.allowed_channels.contains(&event.channel_id) {
+            return;
+        }
+        // Updates without content, like embeds being added to a link, aren't edits.
+        let Some(content) = event.content else {
+            return;
+        };
+        let edit = DiscordMessage::Edited {
+            id: event.id,
+            content,
+            edited_at: event.edited_timestamp,
+        };
+        if let Err(e) = self.tx.send(edit).await {
+            error!("Could not send edited message tx over channel: {e}");
+        }
+    }
+
+    /// Passes deletions of messages in a listened-to channel on to be stored.
+    async fn forward_deletion(&self, channel_id: ChannelId, ids: Vec<MessageId>) {
+        if !self.allowed_channels.contains(&channel_id) {
+            return;
+        }
+        let deletion = DiscordMessage::Deleted(ids);
+        if let Err(e) = self.tx.send(deletion).await {
+            error!("Could not send deleted messages tx over channel: {e}");
         }
     }
 }
@@ -32,40 +106,54 @@ impl Handler {
 #[async_trait]
 impl EventHandler for Handler {
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-        dbg!(&interaction);
-
-        match interaction {
+        let result = match interaction {
             Interaction::Component(ref component) => {
                 if component.data.custom_id.starts_with("recap-") {
-                    crate::services::commands::recap::publish(&ctx, &interaction)
-                        .await
-                        .unwrap();
-                    None
+                    crate::services::commands::recap::publish(&ctx, &interaction).await
                 } else {
-                    println!("Received unknown component: {component:#?}");
-                    None
+                    warn!("Received unknown component {}", component.data.custom_id);
+                    Ok(())
                 }
             }
-            Interaction::Autocomplete(command) => match command.data.name.as_str() {
-                "recap" => {
-                    crate::services::commands::recap::autocomplete(&ctx, &command)
-                        .await
-                        .unwrap();
-                    None
-                }
-                _ => None,
+            Interaction::Autocomplete(ref command) => match command.data.name.as_str() {
+                "recap" => crate::services::commands::recap::autocomplete(&ctx, command).await,
+                _ => Ok(()),
             },
-            Interaction::Command(command) => match command.data.name.as_str() {
-                "recap" => crate::services::commands::recap::run(&ctx, &command)
+            Interaction::Command(ref command) => match command.data.name.as_str() {
+                "recap" => crate::services::commands::recap::run(
+                    &ctx,
+                    command,
+                    &self.config,
+                    &self.summarizer,
+                )
+                .await
+                .map(drop),
+                "search" => crate::services::commands::search::run(&ctx, command, &self.db)
                     .await
-                    .unwrap(),
-                _ => None,
+                    .map(drop),
+                "ask" => crate::services::commands::ask::run(
+                    &ctx,
+                    command,
+                    &self.db,
+                    &self.config,
+                    &*self.provider,
+                    self.embedder.as_deref(),
+                )
+                .await
+                .map(drop),
+                _ => Ok(()),
             },
             _ => {
-                println!("Received unknown interaction: {interaction:#?}");
-                None
+                warn!(
+                    "Received unknown interaction of kind {:?}",
+                    interaction.kind()
+                );
+                Ok(())
             }
         };
+        if let Err(e) = result {
+            error!("Could not handle interaction: {e}");
+        }
     }
 
     async fn message(&self, _: Context, msg: Message) {
@@ -73,24 +161,166 @@ impl EventHandler for Handler {
         if !self.allowed_channels.contains(&msg.channel_id) {
             return;
         }
-        if let Err(e) = self.tx.send(DiscordMessage::Received(msg)).await {
+        metrics()
+            .messages_received
+            .with_label_values(&[&msg.channel_id.to_string()])
+            .inc();
+        if let Err(e) = self.tx.send(DiscordMessage::Received(Box::new(msg))).await {
             error!("Could not send received message tx over channel: {e}");
         }
     }
 
+    async fn message_update(
+        &self,
+        _: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        self.forward_edit(event).await;
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        self.message_delete_bulk(ctx, channel_id, vec![deleted_message_id], guild_id)
+            .await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        _: Option<GuildId>,
+    ) {
+        self.forward_deletion(channel_id, multiple_deleted_messages_ids)
+            .await;
+    }
+
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+        self.health.set_discord_connected(true);
 
         let http = &ctx.http;
 
         stream::iter(ready.guilds)
             .for_each(|guild| async move {
-                println!("Connected to guild: {guild:#?}");
+                info!("Connected to guild {}", guild.id);
                 let _commands = guild
                     .id
-                    .set_commands(http, vec![crate::services::commands::recap::register()])
+                    .set_commands(
+                        http,
+                        vec![
+                            crate::services::commands::recap::register(),
+                            crate::services::commands::search::register(),
+                            crate::services::commands::ask::register(),
+                        ],
+                    )
                     .await;
             })
             .await;
     }
+
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        info!("Shard {} is now {}", event.shard_id, event.new);
+        self.health
+            .set_discord_connected(event.new == ConnectionStage::Connected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::{self, error::TryRecvError, Receiver};
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::db::tests::memory_pool;
+    use crate::gpt::tests::mock_llm_config;
+    use crate::gpt::EchoProvider;
+    use crate::services::supervisor::Supervisor;
+
+    const LISTENED: u64 = 10;
+    const IGNORED: u64 = 20;
+
+    async fn handler() -> (Handler, Receiver<DiscordMessage>) {
+        let config = mock_llm_config(&[], 0).await;
+        let db = Arc::new(memory_pool().await);
+        let provider: Arc<dyn SummaryProvider> = Arc::new(EchoProvider::new(None));
+        let (summarize_tx, _) = mpsc::channel(1);
+        let health = Health::new(
+            db.clone(),
+            Supervisor::new(CancellationToken::new(), 1, Duration::from_secs(1)),
+            summarize_tx.downgrade(),
+            provider.clone(),
+        );
+        let summarizer = Arc::new(MapReduceSummarizer::with_provider(
+            provider.clone(),
+            &config,
+        ));
+        let (tx, rx) = mpsc::channel(10);
+        let handler = Handler::new(
+            tx,
+            HashSet::from([ChannelId::new(LISTENED)]),
+            db,
+            health,
+            Arc::new(config),
+            provider,
+            summarizer,
+        );
+        (handler, rx)
+    }
+
+    fn edit(channel_id: u64, content: Option<&str>) -> MessageUpdateEvent {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "channel_id": channel_id.to_string(),
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn edits_are_forwarded_from_listened_channels_only() {
+        let (handler, mut rx) = handler().await;
+
+        handler.forward_edit(edit(IGNORED, Some("changed"))).await;
+        // Updates without content aren't edits.
+        handler.forward_edit(edit(LISTENED, None)).await;
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+
+        handler.forward_edit(edit(LISTENED, Some("changed"))).await;
+        match rx.try_recv() {
+            Ok(DiscordMessage::Edited { id, content, .. }) => {
+                assert_eq!(id, MessageId::new(1));
+                assert_eq!(content, "changed");
+            }
+            _ => panic!("the edit was not forwarded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn deletions_are_forwarded_from_listened_channels_only() {
+        let (handler, mut rx) = handler().await;
+        let ids = vec![MessageId::new(1), MessageId::new(2)];
+
+        handler
+            .forward_deletion(ChannelId::new(IGNORED), ids.clone())
+            .await;
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+
+        handler
+            .forward_deletion(ChannelId::new(LISTENED), ids.clone())
+            .await;
+        match rx.try_recv() {
+            Ok(DiscordMessage::Deleted(deleted)) => assert_eq!(deleted, ids),
+            _ => panic!("the deletion was not forwarded"),
+        }
+    }
 }
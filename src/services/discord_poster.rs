@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serenity::all::{ChannelId, CreateEmbed, CreateMessage, Http, MessageId};
+use tracing::{info, warn};
+
+use crate::config::DiscordConfig;
+
+/// Discord rejects message content longer than this.
+pub const MESSAGE_CHAR_LIMIT: usize = 2000;
+/// Discord rejects embed descriptions longer than this.
+const EMBED_CHAR_LIMIT: usize = 4096;
+
+/// Posts digests to the Discord channels configured for them.
+pub struct DigestPoster {
+    http: Arc<Http>,
+    default_channel: Option<ChannelId>,
+    channels: HashMap<i64, ChannelId>,
+    embeds: bool,
+}
+
+impl DigestPoster {
+    /// Returns `None` when no digest channels are configured.
+    pub fn from_config(http: Arc<Http>, config: &DiscordConfig) -> Option<Self> {
+        let parse = |id: &String| id.parse::<u64>().ok().map(ChannelId::new);
+
+        let default_channel = config.digest_channel_id.as_ref().and_then(parse);
+        let channels: HashMap<i64, ChannelId> = config
+            .digest_channel_ids
+            .iter()
+            .filter_map(|(source, target)| Some((source.parse().ok()?, parse(target)?)))
+            .collect();
+
+        if default_channel.is_none() && channels.is_empty() {
+            return None;
+        }
+        Some(Self {
+            http,
+            default_channel,
+            channels,
+            embeds: config.digest_embeds,
+        })
+    }
+
+    /// Posts a digest of `source_channel` (or a roll-up, for `None`) to its digest channel, split
+    /// over as many messages as it takes. Returns the id of the first message, or `None` if there
+    /// is nowhere to post this digest.
+    pub async fn post(
+        &self,
+        source_channel: Option<i64>,
+        text: &str,
+    ) -> Result<Option<MessageId>, serenity::Error> {
+        let Some(target) = source_channel
+            .and_then(|id| self.channels.get(&id).copied())
+            .or(self.default_channel)
+        else {
+            return Ok(None);
+        };
+
+        let title = match source_channel {
+            Some(id) => format!("Digest for <#{id}>"),
+            None => "Digest roll-up".to_string(),
+        };
+
+        let messages: Vec<CreateMessage> = if self.embeds {
+            split_message(text, EMBED_CHAR_LIMIT)
+                .into_iter()
+                .enumerate()
+                .map(|(i, part)| {
+                    let embed = CreateEmbed::new().description(part);
+                    let embed = if i == 0 { embed.title(&title) } else { embed };
+                    CreateMessage::new().embed(embed)
+                })
+                .collect()
+        } else {
+            split_message(&format!("**{title}**\n{text}"), MESSAGE_CHAR_LIMIT)
+                .into_iter()
+                .map(|part| CreateMessage::new().content(part))
+                .collect()
+        };
+
+        let mut first_message_id = None;
+        for message in messages {
+            let sent = target.send_message(&*self.http, message).await?;
+            first_message_id.get_or_insert(sent.id);
+        }
+        info!("Posted digest to channel {target}");
+        Ok(first_message_id)
+    }
+}
+
+/// Splits `text` into parts of at most `limit` characters, preferring to break between lines,
+/// then between words, and only then mid-word. Only the line or word break a part is split on is
+/// dropped, so indentation at the start of a part is kept.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut rest = text.trim_end().trim_start_matches('\n');
+
+    while rest.chars().count() > limit {
+        let hard_end = rest
+            .char_indices()
+            .nth(limit)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let window = &rest[..hard_end];
+        let (end, next) = match window
+            .rfind('\n')
+            .or_else(|| window.rfind(' '))
+            .filter(|&i| i > 0)
+        {
+            Some(i) => (i, i + 1),
+            None => {
+                warn!("No line or word break to split a Discord message on");
+                (hard_end, hard_end)
+            }
+        };
+
+        parts.push(rest[..end].trim_end_matches('\n').to_string());
+        rest = rest[next..].trim_start_matches('\n');
+    }
+    if !rest.is_empty() {
+        parts.push(rest.to_string());
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_not_split() {
+        assert_eq!(split_message("hello", 10), vec!["hello"]);
+        assert!(split_message("  \n", 10).is_empty());
+    }
+
+    #[test]
+    fn splits_between_lines_and_keeps_indentation() {
+        let text = "- one\n  - nested\n  - also nested";
+        assert_eq!(
+            split_message(text, 20),
+            vec!["- one\n  - nested", "  - also nested"]
+        );
+    }
+
+    #[test]
+    fn blank_lines_at_the_split_are_dropped() {
+        assert_eq!(
+            split_message("first paragraph\n\n\n    code", 18),
+            vec!["first paragraph", "    code"]
+        );
+    }
+
+    #[test]
+    fn splits_between_words_before_mid_word() {
+        assert_eq!(
+            split_message("alpha beta gamma", 12),
+            vec!["alpha beta", "gamma"]
+        );
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn parts_fit_the_limit_in_characters() {
+        let text = "ünïcödé wörds ".repeat(30);
+        let parts = split_message(&text, 25);
+        assert!(parts.iter().all(|part| part.chars().count() <= 25));
+        assert_eq!(parts.join(" "), text.trim_end());
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::SqlitePool;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::config::EmbeddingsConfig;
+use crate::db;
+use crate::gpt::EmbeddingProvider;
+
+/// Embeds logged messages and new summaries in the background, so semantic search can find them.
+pub struct EmbeddingService {
+    db: Arc<SqlitePool>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    batch_size: i64,
+    interval: Duration,
+}
+
+impl EmbeddingService {
+    pub fn new(
+        db: Arc<SqlitePool>,
+        embedder: Arc<dyn EmbeddingProvider>,
+        batch_size: usize,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            db,
+            embedder,
+            batch_size: batch_size.max(1) as i64,
+            interval,
+        }
+    }
+
+    pub fn from_config(
+        db: Arc<SqlitePool>,
+        embedder: Arc<dyn EmbeddingProvider>,
+        config: &EmbeddingsConfig,
+    ) -> Self {
+        Self::new(
+            db,
+            embedder,
+            config.batch_size,
+            Duration::from_secs(config.interval_seconds),
+        )
+    }
+
+    pub async fn run(&self, shutdown: CancellationToken) {
+        let mut timer = interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = timer.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+            // Unlike summaries, embeddings are cheap to redo, so there is no need to wait for
+            // them; whatever was not stored yet is embedded on the next start.
+            tokio::select! {
+                _ = self.embed_pending() => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    /// Embeds every message and summary the current model has not embedded yet, returning how
+    /// many it embedded. Stops at the first failure; the next call picks up from there.
+    pub async fn embed_pending(&self) -> usize {
+        let model = self.embedder.model().to_string();
+        let mut embedded = 0;
+
+        loop {
+            let messages =
+                match db::fetch_unembedded_messages(&self.db, &model, self.batch_size).await {
+                    Ok(messages) if messages.is_empty() => break,
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        error!("Could not fetch messages to embed: {e}");
+                        return embedded;
+                    }
+                };
+            let texts: Vec<String> = messages
+                .iter()
+                .map(|m| format!("{}: {}", m.display_name, m.content))
+                .collect();
+            let Some(embeddings) = self.embed(&texts, messages.iter().map(|m| m.id)).await else {
+                return embedded;
+            };
+            if let Err(e) = db::insert_message_embeddings(&self.db, &model, &embeddings).await {
+                error!("Could not store message embeddings: {e}");
+                return embedded;
+            }
+            embedded += embeddings.len();
+        }
+
+        loop {
+            let summaries =
+                match db::fetch_unembedded_summaries(&self.db, &model, self.batch_size).await {
+                    Ok(summaries) if summaries.is_empty() => break,
+                    Ok(summaries) => summaries,
+                    Err(e) => {
+                        error!("Could not fetch summaries to embed: {e}");
+                        return embedded;
+                    }
+                };
+            let texts: Vec<String> = summaries.iter().map(|s| s.text.clone()).collect();
+            let Some(embeddings) = self.embed(&texts, summaries.iter().map(|s| s.id)).await else {
+                return embedded;
+            };
+            if let Err(e) = db::insert_summary_embeddings(&self.db, &model, &embeddings).await {
+                error!("Could not store summary embeddings: {e}");
+                return embedded;
+            }
+            embedded += embeddings.len();
+        }
+
+        if embedded > 0 {
+            info!("Embedded {embedded} messages and summaries");
+        }
+        embedded
+    }
+
+    /// Embeds `texts`, pairing each vector with the matching id.
+    async fn embed(
+        &self,
+        texts: &[String],
+        ids: impl Iterator<Item = i64>,
+    ) -> Option<Vec<(i64, Vec<f32>)>> {
+        match self.embedder.embed(texts).await {
+            Ok(embeddings) => Some(ids.zip(embeddings.vectors).collect()),
+            Err(e) => {
+                error!("Could not embed {} texts: {e}", texts.len());
+                None
+            }
+        }
+    }
+}
+
+/// The messages and summaries most similar in meaning to `query`, best first.
+pub async fn semantic_search(
+    db: &SqlitePool,
+    embedder: &dyn EmbeddingProvider,
+    query: &str,
+    channel_id: Option<i64>,
+    guild_id: Option<i64>,
+    limit: i64,
+) -> eyre::Result<Vec<db::SearchHit>> {
+    let vector = embedder
+        .embed(&[query.to_string()])
+        .await?
+        .vectors
+        .pop()
+        .ok_or_else(|| eyre::eyre!("The embedding provider returned no vector"))?;
+    let hits =
+        db::semantic_search(db, embedder.model(), &vector, channel_id, guild_id, limit).await?;
+    Ok(hits)
+}
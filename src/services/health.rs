@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::sync::mpsc::WeakSender;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::summarizer::SummarizeRequest;
+use super::supervisor::{ServiceState, ServiceStatus, Supervisor};
+use crate::db;
+use crate::gpt::SummaryProvider;
+
+/// How long a check of the LLM provider is trusted, so that frequent health checks don't turn
+/// into a stream of API requests.
+const LLM_CHECK_TTL: Duration = Duration::from_secs(60);
+const LLM_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gathers the state of every part of the bot for health checks.
+#[derive(Clone)]
+pub struct Health {
+    db: Arc<SqlitePool>,
+    supervisor: Supervisor,
+    /// Weak, so that health checks don't keep the summary service's channel open at shutdown.
+    summarize_tx: WeakSender<SummarizeRequest>,
+    provider: Arc<dyn SummaryProvider>,
+    discord_connected: Arc<AtomicBool>,
+    /// When the LLM provider was last checked, and whether it was reachable.
+    llm_check: Arc<Mutex<Option<(Instant, bool)>>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// The database answers and no service has failed for good.
+    pub healthy: bool,
+    /// Healthy, connected to Discord, able to reach the LLM, and no service is restarting.
+    pub ready: bool,
+    pub discord_connected: bool,
+    pub database_reachable: bool,
+    pub llm_reachable: bool,
+    pub last_summary_at: Option<NaiveDateTime>,
+    pub last_digest_at: Option<NaiveDateTime>,
+    /// Summarize requests waiting for the summary service to pick them up.
+    pub summarize_queue_depth: usize,
+    /// Summarize jobs waiting in the database, including failed ones waiting to be retried.
+    pub pending_summarize_jobs: Option<i64>,
+    pub services: BTreeMap<&'static str, ServiceStatus>,
+}
+
+impl Health {
+    pub fn new(
+        db: Arc<SqlitePool>,
+        supervisor: Supervisor,
+        summarize_tx: WeakSender<SummarizeRequest>,
+        provider: Arc<dyn SummaryProvider>,
+    ) -> Self {
+        Self {
+            db,
+            supervisor,
+            summarize_tx,
+            provider,
+            discord_connected: Arc::default(),
+            llm_check: Arc::default(),
+        }
+    }
+
+    pub fn set_discord_connected(&self, connected: bool) {
+        self.discord_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Whether the database answers and no service has failed for good. Unlike
+    /// [`Health::report`], never waits on the LLM provider, so it answers quickly.
+    pub async fn healthy(&self) -> bool {
+        self.database_reachable().await && none_failed(&self.supervisor.statuses())
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let database_reachable = self.database_reachable().await;
+        let last_summary_at = db::fetch_last_summary_timestamp(&self.db)
+            .await
+            .ok()
+            .flatten();
+        let last_digest_at = db::fetch_last_digest_timestamp(&self.db)
+            .await
+            .ok()
+            .flatten();
+        let pending_summarize_jobs = db::count_pending_summarize_jobs(&self.db).await.ok();
+        let summarize_queue_depth = self
+            .summarize_tx
+            .upgrade()
+            .map_or(0, |tx| tx.max_capacity() - tx.capacity());
+        let discord_connected = self.discord_connected.load(Ordering::Relaxed);
+        let llm_reachable = self.llm_reachable().await;
+        let services = self.supervisor.statuses();
+
+        let healthy = database_reachable && none_failed(&services);
+        let ready = healthy
+            && discord_connected
+            && llm_reachable
+            && services
+                .values()
+                .all(|status| status.state != ServiceState::Restarting);
+
+        HealthReport {
+            healthy,
+            ready,
+            discord_connected,
+            database_reachable,
+            llm_reachable,
+            last_summary_at,
+            last_digest_at,
+            summarize_queue_depth,
+            pending_summarize_jobs,
+            services,
+        }
+    }
+
+    async fn database_reachable(&self) -> bool {
+        match db::ping(&self.db).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Database health check failed: {e}");
+                false
+            }
+        }
+    }
+
+    async fn llm_reachable(&self) -> bool {
+        // Holding the lock while checking makes concurrent health checks share one request.
+        let mut last_check = self.llm_check.lock().await;
+        if let Some((checked_at, reachable)) = *last_check {
+            if checked_at.elapsed() < LLM_CHECK_TTL {
+                return reachable;
+            }
+        }
+
+        let reachable = match tokio::time::timeout(LLM_CHECK_TIMEOUT, self.provider.check()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                warn!("LLM provider health check failed: {e}");
+                false
+            }
+            Err(_) => {
+                warn!("LLM provider health check timed out after {LLM_CHECK_TIMEOUT:?}");
+                false
+            }
+        };
+        *last_check = Some((Instant::now(), reachable));
+        reachable
+    }
+}
+
+fn none_failed(services: &BTreeMap<&'static str, ServiceStatus>) -> bool {
+    services
+        .values()
+        .all(|status| status.state != ServiceState::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::Extension;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::db::tests::{memory_pool, summarized_message, usage};
+    use crate::gpt::EchoProvider;
+    use crate::http_api::readyz_handler;
+
+    fn health(
+        db: SqlitePool,
+    ) -> (
+        Health,
+        mpsc::Sender<SummarizeRequest>,
+        mpsc::Receiver<SummarizeRequest>,
+    ) {
+        let (summarize_tx, summarize_rx) = mpsc::channel(10);
+        let supervisor = Supervisor::new(CancellationToken::new(), 5, Duration::from_secs(60));
+        let health = Health::new(
+            Arc::new(db),
+            supervisor,
+            summarize_tx.downgrade(),
+            Arc::new(EchoProvider::new(None)),
+        );
+        (health, summarize_tx, summarize_rx)
+    }
+
+    async fn readyz(health: &Health) -> StatusCode {
+        readyz_handler(Extension(health.clone())).await.0
+    }
+
+    #[tokio::test]
+    async fn ready_once_discord_and_the_database_are_up() {
+        let (health, _tx, _rx) = health(memory_pool().await);
+        assert!(health.healthy().await);
+        assert!(!health.report().await.ready);
+        assert_eq!(readyz(&health).await, StatusCode::SERVICE_UNAVAILABLE);
+
+        health.set_discord_connected(true);
+        assert!(health.report().await.ready);
+        assert_eq!(readyz(&health).await, StatusCode::OK);
+
+        health.set_discord_connected(false);
+        assert_eq!(readyz(&health).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_the_database() {
+        let (health, _tx, _rx) = health(memory_pool().await);
+        health.set_discord_connected(true);
+        health.db.close().await;
+
+        let report = health.report().await;
+        assert!(!report.database_reachable);
+        assert!(!report.healthy);
+        assert!(!report.ready);
+        assert!(!health.healthy().await);
+        assert_eq!(readyz(&health).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn reports_queue_depth_and_last_outputs() {
+        let (health, summarize_tx, _summarize_rx) = health(memory_pool().await);
+        let report = health.report().await;
+        assert_eq!(report.summarize_queue_depth, 0);
+        assert!(report.last_summary_at.is_none());
+        assert!(report.last_digest_at.is_none());
+
+        for channel_id in [10, 20, 30] {
+            summarize_tx
+                .send(SummarizeRequest::UnsummarizedMessages { channel_id })
+                .await
+                .unwrap();
+        }
+        let summary_id = summarized_message(&health.db, 1, 10).await;
+        db::insert_daily_digest(
+            &health.db,
+            "digest".to_string(),
+            Some(10),
+            Some(1),
+            vec![summary_id],
+            &usage(),
+        )
+        .await
+        .unwrap();
+
+        let report = health.report().await;
+        assert_eq!(report.summarize_queue_depth, 3);
+        assert_eq!(report.pending_summarize_jobs, Some(0));
+        let summary = db::fetch_summary(&health.db, summary_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.last_summary_at, Some(summary.timestamp));
+        let digest = db::fetch_latest_daily_digest(&health.db, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.last_digest_at, Some(digest.timestamp));
+    }
+}
@@ -1,127 +1,240 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::Write,
-    path::PathBuf,
-};
+use std::collections::HashMap;
+use std::sync::Arc;
 
+use chrono::{DateTime, NaiveDateTime};
+use serenity::all::{Message, Timestamp};
+use sqlx::SqlitePool;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
 use super::{discord_handler::DiscordMessage, summarizer::SummarizeRequest};
+use crate::db::{self, StoredMessage};
+use crate::gpt::Tokenizer;
+use crate::metrics::metrics;
 
 pub struct MessageLogService {
     summarize_tx: Sender<SummarizeRequest>,
     discord_rx: Receiver<DiscordMessage>,
-    message_log_path: PathBuf,
-    log_file_index: usize,
-    curr_file_token_count: usize,
-    message_log: File,
+    db: Arc<SqlitePool>,
+    /// Token count of the messages not yet queued for a summary, per channel.
+    unsummarized_token_counts: HashMap<i64, usize>,
     summary_tokens_threshold: usize,
+    tokenizer: Tokenizer,
+    /// Cancelled once the Discord gateway is closed, after which nothing more arrives.
+    gateway_closed: CancellationToken,
 }
 
 impl MessageLogService {
     pub fn new(
-        message_log_path: PathBuf,
+        db: Arc<SqlitePool>,
         summarize_tx: Sender<SummarizeRequest>,
         discord_rx: Receiver<DiscordMessage>,
         summary_tokens_threshold: usize,
+        tokenizer: Tokenizer,
+        gateway_closed: CancellationToken,
     ) -> Self {
-        let log_file_index: usize = find_last_log_file_index(&message_log_path).unwrap_or(0);
-        info!("{}", log_file_index);
-        let fpath = message_log_path.join(format!("messages_{log_file_index}.txt"));
-        let message_log = OpenOptions::new()
-            .append(true) // Set to append mode
-            .create(true) // Create file if it does not exist
-            .open(&fpath) // Specify the file path
-            .expect("Unable to open messages log");
-
-        let curr_file_token_count = crate::gpt::estimate_token_count(fpath)
-            .expect("Could not estimate token count of file on init");
         Self {
             summarize_tx,
             discord_rx,
-            message_log_path,
-            log_file_index,
-            curr_file_token_count,
-            message_log,
+            db,
+            unsummarized_token_counts: HashMap::new(),
             summary_tokens_threshold,
+            tokenizer,
+            gateway_closed,
         }
     }
 
-    pub async fn run(&mut self) {
-        while let Some(data) = self.discord_rx.recv().await {
-            match data {
-                DiscordMessage::Received(msg) => {
-                    // Check if the file has reached the critical mass, then figure out what we need to do:
-                    // Have we reached the max tokens we want in our request? If so, then increase the log file index
-                    // and emit a summarize request.
-                    let incoming_token_count =
-                        msg.content.chars().count() / crate::gpt::CHARS_PER_TOKEN;
-                    if self.curr_file_token_count + incoming_token_count
-                        > self.summary_tokens_threshold
-                    {
-                        warn!("File has overflowed the allowed token count, creating new file");
-                        let log_file_index = self.log_file_index + 1;
-                        let fpath = self
-                            .message_log_path
-                            .join(format!("messages_{log_file_index}.txt"));
-                        let message_log = OpenOptions::new()
-                            .append(true)
-                            .create(true)
-                            .open(fpath)
-                            .expect("Unable to open messages log"); // TODO: Handle panic.
+    /// Stores received messages until `shutdown` is cancelled, then keeps storing them until the
+    /// Discord gateway, which is shut down at the same time, is closed, and returns.
+    pub async fn run(&mut self, shutdown: CancellationToken) {
+        // Pick up where we left off: messages stored before a restart still count towards the
+        // next summary.
+        match db::fetch_unqueued_messages(&self.db).await {
+            Ok(messages) => {
+                for message in &messages {
+                    *self
+                        .unsummarized_token_counts
+                        .entry(message.channel_id)
+                        .or_default() += self.tokenizer.count(&message.prompt_line());
+                }
+                info!(
+                    "Found {} unsummarized messages across {} channels",
+                    messages.len(),
+                    self.unsummarized_token_counts.len()
+                );
+            }
+            Err(e) => error!("Could not count unsummarized messages: {e}"),
+        }
+        // Channels that went over the threshold before a restart would otherwise wait for their
+        // next message to request a summary.
+        let overflowed: Vec<i64> = self
+            .unsummarized_token_counts
+            .iter()
+            .filter(|(_, &count)| count > self.summary_tokens_threshold)
+            .map(|(&channel_id, _)| channel_id)
+            .collect();
+        for channel_id in overflowed {
+            self.request_summary(channel_id).await;
+        }
 
-                        // Send a request to summarize the previous, full file.
-                        self.summarize_tx
-                            .send(SummarizeRequest::FileWithIndex(self.log_file_index))
-                            .await
-                            .unwrap(); // TODO: Handle panic.
+        loop {
+            tokio::select! {
+                data = self.discord_rx.recv() => match data {
+                    Some(data) => self.handle(data).await,
+                    None => break,
+                },
+                _ = shutdown.cancelled() => {
+                    let handled = self.drain().await;
+                    info!("Stored {handled} messages, edits and deletions received during shutdown");
+                    break;
+                }
+            }
+        }
+    }
 
-                        self.message_log = message_log;
-                        self.log_file_index = log_file_index;
-                        self.curr_file_token_count = 0;
+    /// Stores what Discord sends until the gateway is closed, then what is left in the channel.
+    /// Closing the channel any earlier would drop messages that arrive in the meantime. Returns
+    /// how many messages, edits and deletions were stored.
+    async fn drain(&mut self) -> usize {
+        let mut handled = 0;
+        loop {
+            tokio::select! {
+                data = self.discord_rx.recv() => match data {
+                    Some(data) => {
+                        self.handle(data).await;
+                        handled += 1;
                     }
+                    None => return handled,
+                },
+                _ = self.gateway_closed.cancelled() => break,
+            }
+        }
 
-                    let timestamp = msg.timestamp;
-                    let content = msg.content;
-                    let author = msg.author.name;
-                    if let Err(e) = writeln!(
-                        self.message_log,
-                        "timestamp: {timestamp}, author: {author}, content: {content}"
-                    ) {
-                        error!("Could not write message with content: {content} to log file: {e}");
-                        continue;
-                    }
-                    self.curr_file_token_count += incoming_token_count;
-                    info!(
-                        "Processed message, file has total token count of {}",
-                        self.curr_file_token_count
+        self.discord_rx.close();
+        while let Some(data) = self.discord_rx.recv().await {
+            self.handle(data).await;
+            handled += 1;
+        }
+        handled
+    }
+
+    async fn handle(&mut self, data: DiscordMessage) {
+        match data {
+            DiscordMessage::Received(msg) => {
+                let message = stored_message(&msg);
+
+                if let Err(e) = db::insert_message(&self.db, &message).await {
+                    error!(
+                        "Could not store message with content: {} in DB: {e}",
+                        message.content
                     );
+                    return;
+                }
+
+                // Would this message take the channel over the max tokens we want in a request?
+                // If so, request a summary of everything stored before it, which fills a batch,
+                // and start the next count from this message, which is left for the next batch.
+                let incoming_token_count = self.tokenizer.count(&message.prompt_line());
+                let channel_token_count = self
+                    .unsummarized_token_counts
+                    .get(&message.channel_id)
+                    .copied()
+                    .unwrap_or_default();
+                if channel_token_count + incoming_token_count > self.summary_tokens_threshold {
+                    self.request_summary(message.channel_id).await;
+                }
+                let channel_token_count = self
+                    .unsummarized_token_counts
+                    .entry(message.channel_id)
+                    .or_default();
+                *channel_token_count += incoming_token_count;
+                info!(
+                    "Processed message, unsummarized messages in channel {} have a total token count of {}",
+                    message.channel_id, channel_token_count
+                );
+            }
+            // Edits and deletions go through here too, so they are applied after the message
+            // they change is stored. Token counts are left alone; they only decide when to
+            // request a summary, which re-reads the messages.
+            DiscordMessage::Edited {
+                id,
+                content,
+                edited_at,
+            } => {
+                let edited_at = edited_at.as_ref().map(naive_utc);
+                match db::update_message_content(&self.db, id.get() as i64, &content, edited_at)
+                    .await
+                {
+                    Ok(true) => info!("Updated edited message {id}"),
+                    Ok(false) => {}
+                    Err(e) => error!("Could not update edited message {id} in DB: {e}"),
+                }
+            }
+            DiscordMessage::Deleted(ids) => {
+                let ids: Vec<i64> = ids.iter().map(|id| id.get() as i64).collect();
+                match db::delete_messages(&self.db, &ids).await {
+                    Ok(0) => {}
+                    Ok(count) => info!("Deleted {count} stored messages"),
+                    Err(e) => error!("Could not delete messages {ids:?} in DB: {e}"),
                 }
             }
         }
     }
+
+    /// Asks for the stored messages of `channel_id` to be summarized, and starts counting its
+    /// tokens again from zero.
+    async fn request_summary(&mut self, channel_id: i64) {
+        warn!(
+            "Unsummarized messages in channel {channel_id} have overflowed the allowed token count, requesting a summary"
+        );
+        metrics()
+            .message_batches
+            .with_label_values(&[&channel_id.to_string()])
+            .inc();
+        if let Err(e) = self
+            .summarize_tx
+            .send(SummarizeRequest::UnsummarizedMessages { channel_id })
+            .await
+        {
+            error!("Could not send summarize request over channel: {e}");
+        }
+        self.unsummarized_token_counts.insert(channel_id, 0);
+    }
+}
+
+fn stored_message(msg: &Message) -> StoredMessage {
+    // Prefer the server nickname, then the global display name, then the username.
+    let display_name = msg
+        .member
+        .as_ref()
+        .and_then(|member| member.nick.clone())
+        .or_else(|| msg.author.global_name.clone())
+        .unwrap_or_else(|| msg.author.name.clone());
+
+    StoredMessage {
+        id: msg.id.get() as i64,
+        channel_id: msg.channel_id.get() as i64,
+        guild_id: msg.guild_id.map(|id| id.get() as i64),
+        author_id: msg.author.id.get() as i64,
+        display_name,
+        content: msg.content.clone(),
+        timestamp: naive_utc(&msg.timestamp),
+        reply_to: msg
+            .message_reference
+            .as_ref()
+            .and_then(|reference| reference.message_id)
+            .map(|id| id.get() as i64),
+        edited_at: msg.edited_timestamp.as_ref().map(naive_utc),
+        summary_id: None,
+        job_id: None,
+        deleted_at: None,
+    }
 }
 
-fn find_last_log_file_index(dirpath: &PathBuf) -> Option<usize> {
-    std::fs::read_dir(dirpath)
-        .expect("Directory containing message logs not found")
-        .filter_map(|entry| {
-            entry.ok().and_then(|e| {
-                e.path().file_name().and_then(|name| {
-                    name.to_str().and_then(|s| {
-                        if s.starts_with("messages_") && s.ends_with(".txt") {
-                            s.trim_start_matches("messages_")
-                                .trim_end_matches(".txt")
-                                .parse::<usize>()
-                                .ok()
-                        } else {
-                            None
-                        }
-                    })
-                })
-            })
-        })
-        .max()
+fn naive_utc(timestamp: &Timestamp) -> NaiveDateTime {
+    DateTime::from_timestamp(timestamp.unix_timestamp(), 0)
+        .unwrap_or_default()
+        .naive_utc()
 }
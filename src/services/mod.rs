@@ -1,5 +1,11 @@
+pub mod budget;
 pub mod digests;
 pub mod discord_handler;
+pub mod discord_poster;
+pub mod embeddings;
+pub mod health;
 pub mod message_listener;
+pub mod schedule;
 pub mod summarizer;
+pub mod supervisor;
 pub mod commands;
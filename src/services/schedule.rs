@@ -0,0 +1,172 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+
+use crate::config::ServiceConfig;
+
+/// When digests are produced: either on a fixed interval, or on a cron schedule evaluated in a
+/// timezone so that "daily at 08:00" stays at 08:00 local time across DST changes.
+#[derive(Debug)]
+pub enum DigestSchedule {
+    Interval(Duration),
+    Cron {
+        schedule: Box<Schedule>,
+        timezone: Tz,
+    },
+}
+
+impl DigestSchedule {
+    /// Uses `digest_schedule` if set, otherwise `produce_digest_interval_seconds`.
+    ///
+    /// `digest_schedule` is either a cron expression with a seconds field
+    /// (`"0 0 8 * * *"`) or `"daily at HH:MM"`, optionally followed by a timezone
+    /// (`"daily at 08:00 America/Los_Angeles"`). Without a timezone in the schedule,
+    /// `digest_timezone` is used, defaulting to UTC.
+    pub fn from_config(config: &ServiceConfig) -> eyre::Result<Self> {
+        let Some(spec) = config.digest_schedule.as_deref() else {
+            return Ok(DigestSchedule::Interval(Duration::from_secs(
+                config.produce_digest_interval_seconds,
+            )));
+        };
+
+        let (expression, timezone) = match spec.trim().strip_prefix("daily at ") {
+            Some(rest) => {
+                let mut parts = rest.split_whitespace();
+                let time = parts.next().unwrap_or_default();
+                let (hour, minute) = time
+                    .split_once(':')
+                    .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)))
+                    .filter(|(h, m)| *h < 24 && *m < 60)
+                    .ok_or_else(|| eyre::eyre!("Invalid time in digest schedule: {spec}"))?;
+                let timezone = parts.next();
+                if parts.next().is_some() {
+                    eyre::bail!("Unexpected text after the timezone in digest schedule: {spec}");
+                }
+                (format!("0 {minute} {hour} * * *"), timezone)
+            }
+            None => (spec.to_string(), None),
+        };
+
+        let timezone = timezone
+            .or(config.digest_timezone.as_deref())
+            .map(|name| {
+                name.parse::<Tz>()
+                    .map_err(|e| eyre::eyre!("Invalid digest timezone {name}: {e}"))
+            })
+            .transpose()?
+            .unwrap_or(Tz::UTC);
+        let schedule = Schedule::from_str(&expression)
+            .map_err(|e| eyre::eyre!("Invalid digest schedule {spec}: {e}"))?;
+
+        Ok(DigestSchedule::Cron {
+            schedule: Box::new(schedule),
+            timezone,
+        })
+    }
+
+    /// The first run strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            DigestSchedule::Interval(interval) => {
+                Some(after + chrono::Duration::from_std(*interval).ok()?)
+            }
+            DigestSchedule::Cron { schedule, timezone } => schedule
+                .after(&after.with_timezone(timezone))
+                .next()
+                .map(|next| next.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn config(digest_schedule: Option<&str>, digest_timezone: Option<&str>) -> ServiceConfig {
+        serde_json::from_value(serde_json::json!({
+            "produce_digest_interval_seconds": 3600,
+            "digest_schedule": digest_schedule,
+            "digest_timezone": digest_timezone,
+            "port": 3000,
+            "host": "127.0.0.1",
+            "max_gpt_request_tokens": 2048,
+        }))
+        .unwrap()
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn interval_without_schedule() {
+        let schedule = DigestSchedule::from_config(&config(None, None)).unwrap();
+        assert_eq!(
+            schedule.next_after(utc(2024, 1, 1, 10, 30)),
+            Some(utc(2024, 1, 1, 11, 30))
+        );
+    }
+
+    #[test]
+    fn daily_keeps_local_time_across_dst() {
+        let schedule =
+            DigestSchedule::from_config(&config(Some("daily at 08:00 America/Los_Angeles"), None))
+                .unwrap();
+        // PST is UTC-8 until 2024-03-10, then PDT is UTC-7.
+        let before = schedule.next_after(utc(2024, 3, 9, 12, 0)).unwrap();
+        assert_eq!(before, utc(2024, 3, 9, 16, 0));
+        let after = schedule.next_after(before).unwrap();
+        assert_eq!(after, utc(2024, 3, 10, 15, 0));
+        // And back again on 2024-11-03.
+        let back = schedule.next_after(utc(2024, 11, 3, 16, 0)).unwrap();
+        assert_eq!(back, utc(2024, 11, 4, 16, 0));
+    }
+
+    #[test]
+    fn timezone_falls_back_to_config_then_utc() {
+        let schedule =
+            DigestSchedule::from_config(&config(Some("daily at 08:30"), Some("Europe/Berlin")))
+                .unwrap();
+        assert_eq!(
+            schedule.next_after(utc(2024, 1, 1, 0, 0)),
+            Some(utc(2024, 1, 1, 7, 30))
+        );
+
+        let schedule = DigestSchedule::from_config(&config(Some("daily at 08:30"), None)).unwrap();
+        assert_eq!(
+            schedule.next_after(utc(2024, 1, 1, 8, 30)),
+            Some(utc(2024, 1, 2, 8, 30))
+        );
+    }
+
+    #[test]
+    fn cron_expressions() {
+        let schedule = DigestSchedule::from_config(&config(Some("0 0 */6 * * *"), None)).unwrap();
+        assert_eq!(
+            schedule.next_after(utc(2024, 1, 1, 7, 0)),
+            Some(utc(2024, 1, 1, 12, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for spec in [
+            "daily at 24:00",
+            "daily at 8",
+            "daily at 08:00 Mars/Olympus",
+            "daily at 08:00 UTC tomorrow",
+            "every day",
+        ] {
+            assert!(
+                DigestSchedule::from_config(&config(Some(spec), None)).is_err(),
+                "{spec}"
+            );
+        }
+        assert!(DigestSchedule::from_config(&config(None, None)).is_ok());
+    }
+}
@@ -1,83 +1,439 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
 
 use sqlx::SqlitePool;
 use tokio::sync::mpsc::Receiver;
-use tracing::{error, info};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-use crate::{config::AppConfig, gpt::SummaryConfig};
+use super::budget::Budget;
+use crate::config::AppConfig;
+use crate::db::{self, JobStatus, StoredMessage, SummarizeJob};
+use crate::gpt::{MapReduceSummarizer, Tokenizer};
+use crate::metrics::metrics;
+
+/// How often to look for jobs whose retry delay has passed.
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many tokens of messages fit in one summary request. Leaves room for the prompt so that a
+/// full batch of messages fits in a single request.
+pub fn batch_tokens(config: &AppConfig, tokenizer: &Tokenizer) -> usize {
+    config
+        .service
+        .max_gpt_request_tokens
+        .saturating_sub(tokenizer.count(&config.summary.prompt))
+}
 
 pub enum SummarizeRequest {
-    FileWithIndex(usize),
+    UnsummarizedMessages { channel_id: i64 },
 }
 
 pub struct SummarizerService {
     summarize_rx: Receiver<SummarizeRequest>,
-    message_log_path: PathBuf,
     db: Arc<SqlitePool>,
+    summarizer: Arc<MapReduceSummarizer>,
+    prompt: String,
+    tokenizer: Tokenizer,
+    batch_tokens: usize,
+    max_attempts: i64,
+    retry_seconds: i64,
+    /// Jobs wait while this is exhausted.
+    budget: Option<Arc<Budget>>,
 }
 
 impl SummarizerService {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        message_log_path: PathBuf,
         summarize_rx: Receiver<SummarizeRequest>,
         db: Arc<SqlitePool>,
+        summarizer: Arc<MapReduceSummarizer>,
+        prompt: String,
+        tokenizer: Tokenizer,
+        batch_tokens: usize,
+        max_attempts: u32,
+        retry_seconds: u64,
     ) -> Self {
         Self {
-            message_log_path,
             summarize_rx,
             db,
+            summarizer,
+            prompt,
+            tokenizer,
+            batch_tokens,
+            max_attempts: max_attempts as i64,
+            retry_seconds: retry_seconds as i64,
+            budget: None,
         }
     }
-    pub async fn run(&mut self) {
-        let config = AppConfig::load().unwrap();
-        while let Some(data) = self.summarize_rx.recv().await {
-            match data {
-                SummarizeRequest::FileWithIndex(log_file_index) => {
-                    info!("Summarizing contents of message log file with index {log_file_index}");
-                    let fpath = self
-                        .message_log_path
-                        .join(format!("messages_{log_file_index}.txt"));
-                    let file_contents = match std::fs::read_to_string(&fpath) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            error!("Could not read file to summarize: {e}");
-                            continue;
-                        }
-                    };
-                    let summary = match crate::gpt::summarize(
-                        &file_contents,
-                        SummaryConfig {
-                            max_tokens: config.summary.max_tokens,
-                            model: config.summary.model.to_string(),
-                            prompt: config.summary.prompt.to_string(),
-                            ..SummaryConfig::default()
-                        },
-                    )
-                    .await
-                    {
-                        Ok(txt) => txt,
-                        Err(e) => {
-                            error!("Could not summarize message log: {e}");
-                            continue;
-                        }
-                    };
-                    info!("Summary: {summary}");
-
-                    // Save the summary to the DB.
-                    if let Err(e) = crate::db::insert_summary(&self.db, &summary).await {
-                        error!("Could not insert summary to DB: {e}, contents: {summary}");
-                        continue;
-                    }
-                    info!("Wrote the summary to the DB");
 
-                    // Delete the file with index that it came from.
-                    if let Err(e) = std::fs::remove_file(&fpath) {
-                        error!("Could not delete file at path: {e}");
+    /// Pauses jobs while `budget` is exhausted.
+    pub fn with_budget(mut self, budget: Arc<Budget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub fn from_config(
+        summarize_rx: Receiver<SummarizeRequest>,
+        db: Arc<SqlitePool>,
+        summarizer: Arc<MapReduceSummarizer>,
+        config: &AppConfig,
+    ) -> Self {
+        let tokenizer = Tokenizer::from_config(config);
+        let batch_tokens = batch_tokens(config, &tokenizer);
+        Self::new(
+            summarize_rx,
+            db,
+            summarizer,
+            config.summary.prompt.clone(),
+            tokenizer,
+            batch_tokens,
+            config.service.summarize_job_max_attempts,
+            config.service.summarize_job_retry_seconds,
+        )
+    }
+
+    /// Runs summarize jobs as they come due until `shutdown` is cancelled. A job in progress is
+    /// finished first; the rest stay queued for the next start.
+    pub async fn run(&mut self, shutdown: CancellationToken) {
+        // Anything still in progress was interrupted by a restart.
+        match db::requeue_in_progress_jobs(&self.db).await {
+            Ok(0) => {}
+            Ok(count) => warn!("Requeued {count} interrupted summarize jobs"),
+            Err(e) => error!("Could not requeue interrupted summarize jobs: {e}"),
+        }
+
+        let mut retry_timer = interval(RETRY_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                request = self.summarize_rx.recv() => match request {
+                    Some(SummarizeRequest::UnsummarizedMessages { channel_id }) => {
+                        self.queue_jobs(channel_id, false).await
                     }
+                    None => break,
+                },
+                _ = retry_timer.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            self.run_due_jobs(&shutdown, None).await;
+        }
+    }
+
+    /// Queues every message not yet queued, or only those of `channel_id`, gives failed jobs and
+    /// those waiting to be retried another go, and runs them all to completion, without waiting
+    /// for new messages.
+    pub async fn replay(&self, channel_id: Option<i64>) {
+        match db::retry_summarize_jobs(&self.db, channel_id).await {
+            Ok(0) => {}
+            Ok(count) => info!("Retrying {count} failed or delayed summarize jobs"),
+            Err(e) => error!("Could not retry failed summarize jobs: {e}"),
+        }
 
-                    info!("Deleted summarized messages log file at path: {:?}", fpath);
+        let messages = match db::fetch_unqueued_messages(&self.db).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Could not fetch unqueued messages: {e}");
+                return;
+            }
+        };
+
+        let channel_ids: BTreeSet<i64> = messages
+            .iter()
+            .map(|m| m.channel_id)
+            .filter(|id| channel_id.is_none() || channel_id == Some(*id))
+            .collect();
+        for channel_id in channel_ids {
+            self.queue_jobs(channel_id, true).await;
+        }
+        // Nothing stops a one-off run early.
+        self.run_due_jobs(&CancellationToken::new(), channel_id)
+            .await;
+    }
+
+    /// Splits the channel's messages not yet queued into token-budget sized batches, one job each.
+    /// A last batch with room to spare is left for later messages to fill, unless `flush` is set.
+    async fn queue_jobs(&self, channel_id: i64, flush: bool) {
+        let messages = match db::fetch_unqueued_channel_messages(&self.db, channel_id).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Could not fetch unqueued messages: {e}");
+                return;
+            }
+        };
+
+        let mut rest = messages.as_slice();
+        while !rest.is_empty() {
+            let (batch, remaining) = self.take_batch(rest);
+            // A batch is full when the next message doesn't fit in it.
+            if remaining.is_empty() && !flush {
+                info!(
+                    "Leaving {} messages in channel {channel_id} for the next batch",
+                    batch.len()
+                );
+                return;
+            }
+            let message_ids: Vec<i64> = batch.iter().map(|m| m.id).collect();
+            let guild_id = batch.first().and_then(|m| m.guild_id);
+            match db::insert_summarize_job(&self.db, channel_id, guild_id, &message_ids).await {
+                Ok(job_id) => info!(
+                    "Queued summarize job {job_id} for {} messages in channel {channel_id}",
+                    message_ids.len()
+                ),
+                Err(e) => {
+                    error!("Could not queue summarize job: {e}");
+                    return;
                 }
             }
+            rest = remaining;
         }
     }
+
+    /// Runs due jobs, of `channel_id` if given, until there are none left.
+    async fn run_due_jobs(&self, shutdown: &CancellationToken, channel_id: Option<i64>) {
+        while !shutdown.is_cancelled() {
+            // Due jobs stay queued, and run once the budget allows it again.
+            if let Some(budget) = &self.budget {
+                if budget.exhausted().await {
+                    return;
+                }
+            }
+            let job = match db::claim_next_summarize_job(&self.db, channel_id).await {
+                Ok(Some(job)) => job,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Could not claim next summarize job: {e}");
+                    return;
+                }
+            };
+
+            if let Err(e) = self.run_job(&job).await {
+                error!("Summarize job {} failed: {e}", job.id);
+                match db::fail_summarize_job(
+                    &self.db,
+                    job.id,
+                    &e.to_string(),
+                    self.max_attempts,
+                    self.retry_seconds,
+                )
+                .await
+                {
+                    Ok(JobStatus::Failed) => error!(
+                        "Giving up on summarize job {} after {} attempts. Its messages are \
+                         summarized again by the replay command",
+                        job.id, job.attempts
+                    ),
+                    Ok(_) => info!(
+                        "Retrying summarize job {} in {} seconds",
+                        job.id, self.retry_seconds
+                    ),
+                    Err(e) => error!("Could not record failure of summarize job {}: {e}", job.id),
+                }
+            }
+        }
+    }
+
+    async fn run_job(&self, job: &SummarizeJob) -> eyre::Result<()> {
+        let messages = db::fetch_job_messages(&self.db, job.id).await?;
+        if messages.is_empty() {
+            info!("All messages of summarize job {} were deleted", job.id);
+            db::complete_empty_summarize_job(&self.db, job.id).await?;
+            return Ok(());
+        }
+        info!(
+            "Summarizing a batch of {} messages for job {} (attempt {})",
+            messages.len(),
+            job.id,
+            job.attempts
+        );
+
+        let lines: Vec<String> = messages.iter().map(|m| m.prompt_line()).collect();
+        let summary = self.summarizer.summarize(&self.prompt, &lines).await?;
+        info!("Summary: {}", summary.text);
+
+        // Save the summary to the DB, marking its messages as summarized.
+        db::complete_summarize_job(&self.db, job.id, &summary.text, &summary.usage).await?;
+        info!("Wrote the summary to the DB");
+        metrics().summaries_produced.inc();
+        Ok(())
+    }
+
+    /// Splits off the longest run of oldest messages that fits in the token budget, always
+    /// taking at least one.
+    fn take_batch<'a>(
+        &self,
+        messages: &'a [StoredMessage],
+    ) -> (&'a [StoredMessage], &'a [StoredMessage]) {
+        let mut batch_len = 0;
+        let mut batch_tokens = 0;
+        for message in messages {
+            let tokens = self.tokenizer.count(&message.prompt_line());
+            if batch_len > 0 && batch_tokens + tokens > self.batch_tokens {
+                break;
+            }
+            batch_len += 1;
+            batch_tokens += tokens;
+        }
+        messages.split_at(batch_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::db::tests::{memory_pool, message};
+    use crate::gpt::tests::mock_llm_config;
+    use crate::services::budget::tests::exhausted_budget;
+
+    /// A service summarizing with the mock LLM, answering with `responses` in turn, with room for
+    /// two of the test messages per job.
+    async fn service(responses: &[&str]) -> (SummarizerService, mpsc::Sender<SummarizeRequest>) {
+        let config = mock_llm_config(responses, 0).await;
+        let db = Arc::new(memory_pool().await);
+        let summarizer =
+            Arc::new(MapReduceSummarizer::from_config(&config, Some(db.clone())).unwrap());
+        let (summarize_tx, summarize_rx) = mpsc::channel(10);
+        let mut service = SummarizerService::from_config(summarize_rx, db, summarizer, &config);
+        service.batch_tokens = 2 * service
+            .tokenizer
+            .count(&message(1, 10, "hello").prompt_line());
+        (service, summarize_tx)
+    }
+
+    async fn store_messages(db: &SqlitePool, channel_id: i64, ids: std::ops::RangeInclusive<i64>) {
+        for id in ids {
+            db::insert_message(db, &message(id, channel_id, "hello"))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn summary_texts(db: &SqlitePool) -> Vec<String> {
+        db::fetch_summaries(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|summary| summary.text)
+            .collect()
+    }
+
+    async fn unqueued_ids(db: &SqlitePool) -> Vec<i64> {
+        db::fetch_unqueued_messages(db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn run_summarizes_full_batches_on_request() {
+        let (mut service, summarize_tx) = service(&["First", "Second"]).await;
+        let db = service.db.clone();
+        store_messages(&db, 10, 1..=5).await;
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { service.run(shutdown).await }
+        });
+        summarize_tx
+            .send(SummarizeRequest::UnsummarizedMessages { channel_id: 10 })
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while summary_texts(&db).await.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the full batches were never summarized");
+        shutdown.cancel();
+        running.await.unwrap();
+
+        assert_eq!(summary_texts(&db).await, vec!["First", "Second"]);
+        // The last message waits for the next batch to fill up.
+        assert_eq!(unqueued_ids(&db).await, vec![5]);
+        // Every request was billed at the configured price.
+        let usage = db::fetch_usage_by_model_since(&db, NaiveDateTime::default())
+            .await
+            .unwrap();
+        assert_eq!(usage.len(), 1);
+        assert!(usage[0].cost.unwrap() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn replay_flushes_partial_batches() {
+        let (service, _summarize_tx) = service(&[]).await;
+        let db = service.db.clone();
+        store_messages(&db, 10, 1..=3).await;
+        store_messages(&db, 20, 4..=4).await;
+
+        service.replay(Some(10)).await;
+        assert_eq!(
+            summary_texts(&db).await,
+            vec!["Mock summary #1", "Mock summary #2"]
+        );
+        assert_eq!(unqueued_ids(&db).await, vec![4]);
+
+        service.replay(None).await;
+        assert_eq!(summary_texts(&db).await.len(), 3);
+        assert!(unqueued_ids(&db).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_finishes_jobs_interrupted_by_a_crash() {
+        let (mut service, _summarize_tx) = service(&["Recovered"]).await;
+        let db = service.db.clone();
+        store_messages(&db, 10, 1..=2).await;
+        // A job claimed by a previous run that never finished it.
+        db::insert_summarize_job(&db, 10, Some(1), &[1, 2])
+            .await
+            .unwrap();
+        db::claim_next_summarize_job(&db, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { service.run(shutdown).await }
+        });
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while summary_texts(&db).await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the interrupted job was never finished");
+        shutdown.cancel();
+        running.await.unwrap();
+
+        assert_eq!(summary_texts(&db).await, vec!["Recovered"]);
+        let message = db::fetch_message(&db, 2).await.unwrap().unwrap();
+        assert!(message.summary_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn jobs_wait_while_the_budget_is_exhausted() {
+        let (service, _summarize_tx) = service(&[]).await;
+        let db = service.db.clone();
+        let mut service = service.with_budget(exhausted_budget(db.clone()).await);
+        store_messages(&db, 10, 1..=2).await;
+        db::insert_summarize_job(&db, 10, Some(1), &[1, 2])
+            .await
+            .unwrap();
+
+        service.run_due_jobs(&CancellationToken::new(), None).await;
+        assert!(summary_texts(&db).await.is_empty());
+        assert_eq!(db::count_pending_summarize_jobs(&db).await.unwrap(), 1);
+
+        // Once the budget is lifted, the job runs.
+        service.budget = None;
+        service.run_due_jobs(&CancellationToken::new(), None).await;
+        assert_eq!(summary_texts(&db).await, vec!["Mock summary #1"]);
+    }
 }
@@ -0,0 +1,316 @@
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::task::{self, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::config::ServiceConfig;
+
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Running,
+    /// Crashed, and waiting to be restarted.
+    Restarting,
+    /// Returned, either on shutdown or because it had nothing left to do.
+    Stopped,
+    /// Crashed too often and was given up on.
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ServiceStatus {
+    pub state: ServiceState,
+    pub started_at: DateTime<Utc>,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Runs background services, restarting any that panic with exponential backoff. A service that
+/// panics more than `max_restarts` times within `restart_window` is given up on, and the whole
+/// process shuts down, so that whatever runs it can restart it from scratch.
+#[derive(Clone)]
+pub struct Supervisor {
+    statuses: Arc<Mutex<BTreeMap<&'static str, ServiceStatus>>>,
+    /// Why the supervisor gave up, if it did.
+    failure: Arc<Mutex<Option<String>>>,
+    shutdown: CancellationToken,
+    max_restarts: usize,
+    restart_window: Duration,
+    /// How long to wait before the first restart, doubling with every further crash within
+    /// `restart_window`, up to `max_backoff`.
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: CancellationToken, max_restarts: u32, restart_window: Duration) -> Self {
+        Self {
+            statuses: Arc::default(),
+            failure: Arc::default(),
+            shutdown,
+            max_restarts: max_restarts as usize,
+            restart_window,
+            initial_backoff: INITIAL_RESTART_BACKOFF,
+            max_backoff: MAX_RESTART_BACKOFF,
+        }
+    }
+
+    pub fn from_config(shutdown: CancellationToken, config: &ServiceConfig) -> Self {
+        Self::new(
+            shutdown,
+            config.max_service_restarts,
+            Duration::from_secs(config.service_restart_window_seconds),
+        )
+    }
+
+    /// Runs the future `service` makes until it returns or shutdown, making a new one each time
+    /// it panics. The future is given the shutdown token to stop on.
+    pub fn spawn<F, Fut>(&self, name: &'static str, service: F) -> JoinHandle<()>
+    where
+        F: FnMut(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        task::spawn(async move { supervisor.supervise(name, service).await })
+    }
+
+    /// The state of every service, by name.
+    pub fn statuses(&self) -> BTreeMap<&'static str, ServiceStatus> {
+        self.statuses.lock().clone()
+    }
+
+    /// Why the supervisor shut the process down, if it did.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().clone()
+    }
+
+    async fn supervise<F, Fut>(&self, name: &'static str, mut service: F)
+    where
+        F: FnMut(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut recent_failures: VecDeque<Instant> = VecDeque::new();
+        loop {
+            info!("Running {name}");
+            self.update(name, |status| {
+                status.state = ServiceState::Running;
+                status.started_at = Utc::now();
+            });
+
+            // Running the service in its own task catches its panics.
+            let result = task::spawn(service(self.shutdown.clone())).await;
+            let reason = match result {
+                Ok(()) => {
+                    info!("{name} stopped");
+                    self.update(name, |status| status.state = ServiceState::Stopped);
+                    return;
+                }
+                Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                Err(e) => e.to_string(),
+            };
+            error!("{name} crashed: {reason}");
+
+            let now = Instant::now();
+            recent_failures.push_back(now);
+            while recent_failures
+                .front()
+                .is_some_and(|&failed_at| now.duration_since(failed_at) > self.restart_window)
+            {
+                recent_failures.pop_front();
+            }
+
+            if recent_failures.len() > self.max_restarts {
+                let failure = format!(
+                    "{name} crashed {} times in {:?}, last with: {reason}",
+                    recent_failures.len(),
+                    self.restart_window
+                );
+                error!("Giving up: {failure}");
+                self.update(name, |status| {
+                    status.state = ServiceState::Failed;
+                    status.last_error = Some(reason);
+                    status.last_error_at = Some(Utc::now());
+                });
+                self.failure.lock().get_or_insert(failure);
+                self.shutdown.cancel();
+                return;
+            }
+
+            let backoff = self
+                .initial_backoff
+                .saturating_mul(1 << (recent_failures.len() - 1).min(16))
+                .min(self.max_backoff);
+            warn!("Restarting {name} in {backoff:?}");
+            self.update(name, |status| {
+                status.state = ServiceState::Restarting;
+                status.restarts += 1;
+                status.last_error = Some(reason);
+                status.last_error_at = Some(Utc::now());
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = self.shutdown.cancelled() => {
+                    self.update(name, |status| status.state = ServiceState::Stopped);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn update(&self, name: &'static str, change: impl FnOnce(&mut ServiceStatus)) {
+        let mut statuses = self.statuses.lock();
+        let status = statuses.entry(name).or_insert_with(|| ServiceStatus {
+            state: ServiceState::Running,
+            started_at: Utc::now(),
+            restarts: 0,
+            last_error: None,
+            last_error_at: None,
+        });
+        change(status);
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn supervisor(max_restarts: u32, initial_backoff: Duration) -> Supervisor {
+        Supervisor {
+            initial_backoff,
+            max_backoff: initial_backoff * 4,
+            ..Supervisor::new(
+                CancellationToken::new(),
+                max_restarts,
+                Duration::from_secs(60),
+            )
+        }
+    }
+
+    /// A service that panics on its first `panics` runs, then runs until shutdown.
+    fn flaky_service(
+        panics: u32,
+    ) -> (
+        Arc<AtomicU32>,
+        impl FnMut(CancellationToken) -> futures::future::BoxFuture<'static, ()>,
+    ) {
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        let service = move |shutdown: CancellationToken| {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if run < panics {
+                    panic!("crash #{}", run + 1);
+                }
+                shutdown.cancelled().await;
+            }) as futures::future::BoxFuture<'static, ()>
+        };
+        (runs, service)
+    }
+
+    /// Waits for the status of the service to satisfy `done`, and returns it.
+    async fn wait_until(
+        supervisor: &Supervisor,
+        done: impl Fn(&ServiceStatus) -> bool,
+    ) -> ServiceStatus {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(status) = supervisor.statuses().get("flaky").filter(|s| done(s)) {
+                    return status.clone();
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("the service never got there")
+    }
+
+    #[tokio::test]
+    async fn restarts_crashed_services() {
+        let supervisor = supervisor(5, Duration::from_millis(200));
+        let (runs, service) = flaky_service(2);
+        let handle = supervisor.spawn("flaky", service);
+
+        let status = wait_until(&supervisor, |s| s.state == ServiceState::Restarting).await;
+        assert_eq!(status.restarts, 1);
+        assert_eq!(status.last_error.as_deref(), Some("crash #1"));
+
+        // It crashes once more after the restart, and then keeps running.
+        let status = wait_until(&supervisor, |s| {
+            s.state == ServiceState::Running && s.restarts == 2
+        })
+        .await;
+        assert_eq!(status.last_error.as_deref(), Some("crash #2"));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(supervisor.failure().is_none());
+
+        supervisor.shutdown.cancel();
+        handle.await.unwrap();
+        assert_eq!(supervisor.statuses()["flaky"].state, ServiceState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_crashes() {
+        let supervisor = supervisor(2, Duration::from_millis(1));
+        let (runs, service) = flaky_service(u32::MAX);
+        let handle = supervisor.spawn("flaky", service);
+        tokio::time::timeout(Duration::from_secs(10), handle)
+            .await
+            .expect("the supervisor never gave up")
+            .unwrap();
+
+        // The first crash and two restarts are allowed; the third crash is one too many.
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        let status = &supervisor.statuses()["flaky"];
+        assert_eq!(status.state, ServiceState::Failed);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("crash #3"));
+        let failure = supervisor.failure().unwrap();
+        assert!(failure.starts_with("flaky crashed 3 times"), "{failure}");
+        assert!(supervisor.shutdown.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn crashes_outside_the_window_are_forgotten() {
+        let supervisor = Supervisor {
+            restart_window: Duration::ZERO,
+            ..supervisor(1, Duration::from_millis(1))
+        };
+        let (runs, service) = flaky_service(5);
+        let handle = supervisor.spawn("flaky", service);
+
+        let status = wait_until(&supervisor, |s| {
+            s.state == ServiceState::Running && s.restarts == 5
+        })
+        .await;
+        assert_eq!(runs.load(Ordering::SeqCst), 6);
+        assert_eq!(status.restarts, 5);
+        assert!(supervisor.failure().is_none());
+
+        supervisor.shutdown.cancel();
+        handle.await.unwrap();
+    }
+}